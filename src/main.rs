@@ -1,18 +1,31 @@
 use futures::stream::{self, StreamExt};
 use reqwest::Client;
-use serde::Deserialize;
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env, fmt, io::{self, Write},
-    path::Path, process::Command,
+    path::{Path, PathBuf}, process::Command,
     error::Error as StdError
 };
 use tokio::{fs, process::Command as TokioCommand};
 use version_compare::Version;
 
+/// Like `println!`, for progress and notes. They go to stderr instead while
+/// stdout carries JSON.
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if crate::output::is_machine() {
+            eprintln!($($arg)*)
+        } else {
+            println!($($arg)*)
+        }
+    };
+}
+
 mod types {
-    use serde::Deserialize;
+    use serde::{Deserialize, Serialize};
 
     #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
     pub struct AurResponse {
         pub version: u8,
         #[serde(rename = "type")]
@@ -21,18 +34,50 @@ mod types {
         pub results: Option<Vec<AurPackage>>,
     }
 
-    #[derive(Debug, Deserialize, Clone)]
+    /// A package from the AUR RPC. Serialized with snake_case field names.
+    #[derive(Debug, Deserialize, Serialize, Clone)]
+    #[allow(dead_code)]
     pub struct AurPackage {
-        #[serde(rename = "Name")]
+        #[serde(rename(deserialize = "Name"))]
         pub name: String,
-        #[serde(rename = "Version")]
+        #[serde(rename(deserialize = "PackageBase"))]
+        pub package_base: Option<String>,
+        #[serde(rename(deserialize = "Version"))]
         pub version: String,
-        #[serde(rename = "Description")]
+        #[serde(rename(deserialize = "Description"))]
         pub description: Option<String>,
-        #[serde(rename = "URL")]
+        #[serde(rename(deserialize = "URL"))]
         pub url: Option<String>,
-        #[serde(rename = "NumVotes")]
+        #[serde(rename(deserialize = "NumVotes"))]
         pub num_votes: Option<u32>,
+        #[serde(rename(deserialize = "Maintainer"))]
+        pub maintainer: Option<String>,
+        /// When the package was flagged out-of-date, in seconds since the epoch.
+        #[serde(rename(deserialize = "OutOfDate"))]
+        pub out_of_date: Option<u64>,
+        /// Dependency fields are only returned by info lookups and the metadata dump.
+        #[serde(default, rename(deserialize = "Depends"), skip_serializing_if = "Option::is_none")]
+        pub depends: Option<Vec<String>>,
+        #[serde(default, rename(deserialize = "MakeDepends"), skip_serializing_if = "Option::is_none")]
+        pub make_depends: Option<Vec<String>>,
+        #[serde(default, rename(deserialize = "CheckDepends"), skip_serializing_if = "Option::is_none")]
+        pub check_depends: Option<Vec<String>>,
+        #[serde(default, rename(deserialize = "Provides"), skip_serializing_if = "Option::is_none")]
+        pub provides: Option<Vec<String>>,
+        #[serde(default, rename(deserialize = "Conflicts"), skip_serializing_if = "Option::is_none")]
+        pub conflicts: Option<Vec<String>>,
+        #[serde(default, rename(deserialize = "Replaces"), skip_serializing_if = "Option::is_none")]
+        pub replaces: Option<Vec<String>>,
+    }
+
+    /// A package from the sync repositories.
+    #[derive(Debug, Serialize, Clone)]
+    pub struct RepoPackage {
+        pub repo: String,
+        pub name: String,
+        pub version: String,
+        pub description: Option<String>,
+        pub url: Option<String>,
     }
 }
 
@@ -44,6 +89,8 @@ enum AurorusError {
     Network(reqwest::Error),
     Io(io::Error),
     Message(String),
+    /// A command was called with the wrong arguments; holds its usage line.
+    Usage(String),
 }
 
 impl fmt::Display for AurorusError {
@@ -52,6 +99,7 @@ impl fmt::Display for AurorusError {
             Self::Network(e) => write!(f, "Network error: {}", e),
             Self::Io(e) => write!(f, "I/O error: {}", e),
             Self::Message(s) => write!(f, "{}", s),
+            Self::Usage(s) => write!(f, "Usage: {}", s),
         }
     }
 }
@@ -77,513 +125,4407 @@ impl From<String> for AurorusError {
 
 type Result<T> = std::result::Result<T, AurorusError>;
 
-mod aur {
+mod paths {
     use super::*;
 
-    pub async fn search(client: &Client, query: &str) -> Result<AurResponse> {
-        let url = format!("https://aur.archlinux.org/rpc/?v=5&type=search&arg={}", query);
-        let resp = client.get(&url).send().await?;
+    pub fn cache_dir() -> String {
+        format!("/home/{}/.cache/aurorus",
+                env::var("USER").unwrap_or_else(|_| "user".to_string()))
+    }
 
-        if !resp.status().is_success() {
-            return Err(format!("HTTP error: {}", resp.status()).into());
-        }
+    pub fn chroot_dir() -> PathBuf {
+        Path::new(&cache_dir()).join("chroot")
+    }
 
-        Ok(resp.json().await?)
+    pub fn state_dir() -> PathBuf {
+        let base = env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&env::var("HOME").unwrap_or_default()).join(".local/state"));
+        base.join("aurorus")
     }
 
-    pub async fn fetch_srcinfo(client: &Client, package: &str) -> Result<String> {
-        let url = format!("https://aur.archlinux.org/cgit/aur.git/plain/.SRCINFO?h={}", package);
-        let resp = client.get(&url).send().await?;
+    pub fn log_dir() -> PathBuf {
+        state_dir().join("logs")
+    }
 
-        if !resp.status().is_success() {
-            return Err(format!("Failed to fetch .SRCINFO for {}: HTTP {}", package, resp.status()).into());
-        }
+    pub fn pins_file() -> PathBuf {
+        state_dir().join("pins.json")
+    }
 
-        Ok(resp.text().await?)
+    pub fn history_file() -> PathBuf {
+        state_dir().join("history.jsonl")
     }
 
-    pub fn parse_dependencies(srcinfo: &str) -> Vec<String> {
-        srcinfo.lines()
-            .filter_map(|line| {
-                let trimmed = line.trim();
-                if trimmed.starts_with("depends =") {
-                    trimmed.split('=').nth(1).map(|dep| dep.trim().to_string())
-                } else {
-                    None
-                }
-            })
-            .collect()
+    /// When `update` last finished without errors and was not cancelled, in
+    /// seconds since the epoch.
+    pub fn last_update_file() -> PathBuf {
+        state_dir().join("last-update")
     }
 
-    pub async fn clone_package_repo(package: &str) -> Result<String> {
-        let repo_url = format!("https://aur.archlinux.org/{}.git", package);
-        let cache_dir = format!("/home/{}/.cache/aurorus",
-                              env::var("USER").unwrap_or_else(|_| "user".to_string()));
-        let dest = format!("{}/{}", cache_dir, package);
+    /// Recent AUR RPC responses.
+    pub fn response_cache_dir() -> PathBuf {
+        Path::new(&cache_dir()).join("responses")
+    }
 
-        if !Path::new(&cache_dir).exists() {
-            fs::create_dir_all(&cache_dir).await?;
-        }
+    /// Downloaded copy of the AUR metadata dump.
+    pub fn metadata_file() -> PathBuf {
+        Path::new(&cache_dir()).join("packages-meta-ext-v1.json.gz")
+    }
 
-        if Path::new(&dest).exists() {
-            println!("Directory {} already exists. Removing...", dest);
-            fs::remove_dir_all(&dest).await?;
-        }
+    /// Copies of every package file aurorus built and installed.
+    pub fn archive_dir() -> PathBuf {
+        Path::new(&cache_dir()).join("archive")
+    }
 
-        println!("Cloning {} into {} ...", repo_url, dest);
-        let status = Command::new("git")
-            .args(["clone", &repo_url, &dest])
-            .status()?;
+    pub fn config_file() -> PathBuf {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(&env::var("HOME").unwrap_or_default()).join(".config"));
+        base.join("aurorus").join("aurorus.conf")
+    }
+}
 
-        if !status.success() {
-            return Err(format!("Failed to clone repository for {}.", package).into());
-        }
+mod clock {
+    use std::time::{SystemTime, UNIX_EPOCH};
 
-        Ok(dest)
+    pub fn unix_now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
     }
-}
 
-mod pacman {
-    use super::*;
+    /// Splits seconds since the epoch into UTC (year, month, day, hour, minute, second).
+    fn civil(secs: u64) -> (i64, u64, u64, u64, u64, u64) {
+        let days = (secs / 86_400) as i64;
+        let rem = secs % 86_400;
 
-    pub fn search(query: &str) -> Vec<String> {
-        Command::new("pacman")
-            .arg("-Ss")
-            .arg(query)
-            .output()
-            .map(|output| {
-                String::from_utf8_lossy(&output.stdout)
-                    .lines()
-                    .map(|line| line.to_string())
-                    .collect()
-            })
-            .unwrap_or_default()
+        // Howard Hinnant's days_from_civil, inverted.
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u64;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u64;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        (year, month, day, rem / 3600, rem % 3600 / 60, rem % 60)
     }
 
-    pub fn is_installed(package: &str) -> bool {
-        Command::new("pacman")
-            .args(["-Q", package])
-            .stdout(std::process::Stdio::null())
-            .stderr(std::process::Stdio::null())
-            .status()
-            .map_or(false, |status| status.success())
+    /// `YYYYMMDDTHHMMSSZ`, for file names that sort by time.
+    pub fn file_stamp(secs: u64) -> String {
+        let (y, mo, d, h, mi, s) = civil(secs);
+        format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", y, mo, d, h, mi, s)
     }
 
-    pub fn get_installed_aur_packages() -> Result<Vec<(String, String)>> {
-        let output = Command::new("pacman").args(["-Qm"]).output()?;
-        let installed = String::from_utf8_lossy(&output.stdout);
+    /// `YYYY-MM-DD HH:MM:SS UTC`, for people.
+    pub fn format(secs: u64) -> String {
+        let (y, mo, d, h, mi, s) = civil(secs);
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", y, mo, d, h, mi, s)
+    }
 
-        let packages = installed
-            .lines()
-            .filter_map(|line| {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() >= 2 {
-                    Some((parts[0].to_string(), parts[1].to_string()))
-                } else {
-                    None
-                }
-            })
-            .collect();
+    /// Reverses `file_stamp`.
+    pub fn parse_file_stamp(stamp: &str) -> Option<u64> {
+        let digits: String = stamp.chars().filter(char::is_ascii_digit).collect();
+        if digits.len() != 14 {
+            return None;
+        }
+        let field = |range: std::ops::Range<usize>| digits[range].parse::<i64>().ok();
+        let (y, mo, d) = (field(0..4)?, field(4..6)?, field(6..8)?);
+        let (h, mi, s) = (field(8..10)?, field(10..12)?, field(12..14)?);
 
-        Ok(packages)
+        // days_from_civil
+        let y = if mo <= 2 { y - 1 } else { y };
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let doy = (153 * (if mo > 2 { mo - 3 } else { mo + 9 }) + 2) / 5 + d - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        let days = era * 146_097 + doe - 719_468;
+
+        u64::try_from(days * 86_400 + h * 3600 + mi * 60 + s).ok()
     }
-}
 
-mod display {
-    use super::*;
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-    pub fn print_package(index: usize, pkg: &AurPackage) {
-        let installed = if pacman::is_installed(&pkg.name) { " (Installed)" } else { "" };
-        println!("{}. {} ({}){}", index, pkg.name, pkg.version, installed);
-        if let Some(desc) = &pkg.description {
-            println!("   description: {}", desc);
+        #[test]
+        fn parse_file_stamp_known_times() {
+            assert_eq!(parse_file_stamp("19700101T000000Z"), Some(0));
+            assert_eq!(parse_file_stamp("20000229T120000Z"), Some(951_825_600));
+            assert_eq!(parse_file_stamp("20241231T235959Z"), Some(1_735_689_599));
         }
-        println!("   Votes: {}", pkg.num_votes.unwrap_or(0));
-        println!("-------------------------");
-    }
 
-    pub fn print_official_pkg(index: usize, line: &str, description: Option<&str>) {
-        if let Some(repo_start) = line.find('[') {
-            let parts: Vec<&str> = line[..repo_start].trim().split_whitespace().collect();
-            if !parts.is_empty() {
-                let name = parts[0];
-                let version = parts.get(1).unwrap_or(&"");
-                let pkg_name = name.split('/').last().unwrap_or(name);
-                let installed = if pacman::is_installed(pkg_name) { " (Installed)" } else { "" };
-                println!("{}. {} ({}){}", index, name, version, installed);
-                if let Some(desc) = description {
-                    println!("   description: {}", desc);
-                }
+        #[test]
+        fn parse_file_stamp_reverses_file_stamp() {
+            for secs in [0, 59, 86_399, 951_782_400, 1_700_000_000, 4_102_444_800] {
+                assert_eq!(parse_file_stamp(&file_stamp(secs)), Some(secs));
             }
         }
-        println!("-------------------------");
-    }
 
-    pub fn print_help() {
-        println!("Available commands:");
-        println!("  search, s <package>     Search for a package in the AUR and official repositories.");
-        println!("  install, i <package>    Install a package from the AUR or official repositories.");
-        println!("  uninstall, ui <package> Uninstall a package.");
-        println!("  update, up              Update installed AUR packages and official packages.");
-        println!("  help                    Show this help message.");
-        println!("  exit                    Exit the application.");
+        #[test]
+        fn parse_file_stamp_rejects_garbage() {
+            assert_eq!(parse_file_stamp("latest"), None);
+            assert_eq!(parse_file_stamp("2024010"), None);
+            assert_eq!(parse_file_stamp("19691231T235959Z"), None);
+        }
     }
 }
 
-mod actions {
+mod logs {
     use super::*;
+    use std::{process::{ExitStatus, Stdio}, sync::Arc, time::Instant};
+    use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWriteExt}, sync::Mutex};
 
-    pub async fn search_packages(client: &Client, query: &str) -> Result<()> {
-        // Process AUR results
-        let aur_response = aur::search(client, query).await?;
-        let mut aur_packages = aur_response.results.unwrap_or_default();
+    const TRAILER: &str = "==> aurorus: exit status ";
 
-        // Sort by votes - ascending order (least votes first)
-        aur_packages.sort_by(|a, b| a.num_votes.cmp(&b.num_votes));
+    /// A finished or interrupted build found in the log directory.
+    pub struct Entry {
+        pub path: PathBuf,
+        pub started: Option<u64>,
+        /// Exit code (or "signal") and duration in seconds, if the build finished.
+        pub result: Option<(String, u64)>,
+    }
 
-        // Get official packages
-        let official_packages = pacman::search(query);
+    /// Runs `cmd` with its output saved to `logs/<pkgbase>/<timestamp>.log`.
+    /// With `echo` the output is also shown on the terminal as it arrives.
+    pub async fn run(mut cmd: TokioCommand, pkgbase: &str, echo: bool) -> Result<(ExitStatus, PathBuf)> {
+        let dir = paths::log_dir().join(pkgbase);
+        fs::create_dir_all(&dir).await?;
+        let path = dir.join(format!("{}.log", clock::file_stamp(clock::unix_now())));
+        let log = Arc::new(Mutex::new(fs::File::create(&path).await?));
 
-        // Count official packages to determine numbering
-        let mut official_count = 0;
-        let mut lines = official_packages.iter().peekable();
-        while let Some(line) = lines.peek() {
-            if !line.starts_with(char::is_whitespace) && line.find('[').is_some() {
-                official_count += 1;
-            }
-            lines.next();
+        if !echo {
+            cmd.stdin(Stdio::null());
         }
+        let started = Instant::now();
+        let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
 
-        // Set starting index for AUR packages (total packages count)
-        let total_packages = aur_packages.len() + official_count;
-        let mut index = total_packages;
+        let (out, err, status) = tokio::join!(
+            tee(stdout, log.clone(), echo.then(io::stdout)),
+            tee(stderr, log.clone(), echo.then(io::stderr)),
+            child.wait(),
+        );
+        out?;
+        err?;
+        let status = status?;
 
-        // Display AUR packages with decreasing indices
-        for pkg in &aur_packages {
-            display::print_package(index, pkg);
-            index -= 1;
-        }
+        let code = status.code().map_or_else(|| "signal".to_string(), |code| code.to_string());
+        let trailer = format!("\n{}{} after {}s\n", TRAILER, code, started.elapsed().as_secs());
+        log.lock().await.write_all(trailer.as_bytes()).await?;
 
-        // Reset lines iterator and display official packages with continuing indices
-        index = official_count;
-        let mut lines = official_packages.iter().peekable();
-        while let Some(line) = lines.next() {
-            if !line.starts_with(char::is_whitespace) && line.find('[').is_some() {
-                let description = lines
-                    .next()
-                    .filter(|desc_line| desc_line.starts_with(char::is_whitespace))
-                    .map(|desc_line| desc_line.trim());
+        Ok((status, path))
+    }
 
-                display::print_official_pkg(index, line, description);
-                index -= 1;
+    async fn tee<R, W>(mut reader: R, log: Arc<Mutex<fs::File>>, mut echo: Option<W>) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: Write,
+    {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(());
+            }
+            log.lock().await.write_all(&buf[..n]).await?;
+            if let Some(out) = echo.as_mut() {
+                out.write_all(&buf[..n])?;
+                out.flush()?;
             }
         }
+    }
 
-        Ok(())
+    /// Logs for `pkgbase`, newest first.
+    pub fn entries(pkgbase: &str) -> Result<Vec<Entry>> {
+        let dir = paths::log_dir().join(pkgbase);
+        let mut paths: Vec<PathBuf> = match std::fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "log"))
+                .collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        paths.sort();
+        paths.reverse();
+
+        Ok(paths.into_iter().map(|path| {
+            let started = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(clock::parse_file_stamp);
+            let result = std::fs::read_to_string(&path).ok().and_then(|contents| {
+                let (code, secs) = contents.lines().last()?.strip_prefix(TRAILER)?.split_once(" after ")?;
+                Some((code.to_string(), secs.trim_end_matches('s').parse().ok()?))
+            });
+            Entry { path, started, result }
+        }).collect())
     }
+}
 
-    async fn handle_dependencies(client: &Client, package: &str) -> Result<()> {
-        println!("Fetching .SRCINFO for {}...", package);
-        let srcinfo = aur::fetch_srcinfo(client, package).await?;
-        let deps = aur::parse_dependencies(&srcinfo);
+mod config {
+    use super::*;
+    use std::sync::OnceLock;
 
-        if deps.is_empty() {
-            println!("No dependencies found for {}.", package);
-            return Ok(());
-        }
+    static CONFIG: OnceLock<Config> = OnceLock::new();
 
-        println!("Found dependencies:");
-        let mut missing = Vec::new();
-        for dep in &deps {
-            let is_installed = pacman::is_installed(dep);
-            println!("  {} {}", dep, if is_installed { "(installed)" } else { "(missing)" });
-            if !is_installed {
-                missing.push(dep.clone());
-            }
-        }
+    /// Settings read from `aurorus.conf`, which uses the same `Key = Value`
+    /// format as pacman.conf.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How many independent pkgbases may build at the same time.
+        pub build_jobs: usize,
+        /// Where missing `validpgpkeys` are fetched from.
+        pub gpg_keyserver: String,
+        /// A local keyring file offered as an alternative to the keyserver.
+        pub gpg_keyring: Option<PathBuf>,
+        /// Packages (or globs) never offered as AUR updates. Includes
+        /// pacman.conf's IgnorePkg.
+        pub ignore: Vec<String>,
+        /// Sync databases older than this count as stale before `pacman -S`.
+        pub sync_db_max_age_hours: u64,
+        /// Answer AUR queries from the metadata dump instead of the RPC.
+        pub offline: bool,
+        /// URL or local file of the AUR metadata dump.
+        pub metadata_source: String,
+        /// A downloaded dump older than this is fetched again.
+        pub metadata_max_age_hours: u64,
+        /// Seconds to wait for a connection to the AUR.
+        pub connect_timeout: u64,
+        /// Seconds to wait for more data on an open connection.
+        pub read_timeout: u64,
+        /// How often a timed out, rate limited, or failed request is retried.
+        pub max_retries: u32,
+        /// Proxy for HTTP and HTTPS, also passed to git and makepkg.
+        pub proxy: Option<String>,
+        /// Comma separated hosts that bypass the proxy.
+        pub no_proxy: Option<String>,
+        /// PEM files with CA certificates to trust in addition to the system's.
+        pub ca_bundles: Vec<PathBuf>,
+        /// Seconds AUR RPC responses are reused; 0 disables caching.
+        pub cache_ttl: u64,
+    }
 
-        if missing.is_empty() {
-            println!("All dependencies for {} are satisfied.", package);
-            return Ok(());
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                build_jobs: 2,
+                gpg_keyserver: "hkps://keyserver.ubuntu.com".to_string(),
+                gpg_keyring: None,
+                ignore: Vec::new(),
+                sync_db_max_age_hours: 24,
+                offline: false,
+                metadata_source: "https://aur.archlinux.org/packages-meta-ext-v1.json.gz".to_string(),
+                metadata_max_age_hours: 24,
+                connect_timeout: 10,
+                read_timeout: 30,
+                max_retries: 3,
+                proxy: None,
+                no_proxy: None,
+                ca_bundles: Vec::new(),
+                cache_ttl: 300,
+            }
         }
+    }
 
-        println!("\nDo you want to install {} missing dependencies? (y/N):", missing.len());
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    pub fn get() -> &'static Config {
+        CONFIG.get_or_init(Config::default)
+    }
 
-        if input.trim().eq_ignore_ascii_case("y") {
-            for dep in missing {
-                println!("Installing dependency {}...", dep);
-                let package_dir = aur::clone_package_repo(&dep).await?;
+    impl Config {
+        pub fn is_ignored(&self, package: &str) -> bool {
+            self.ignore.iter().any(|pattern| glob_match(pattern, package))
+        }
+    }
 
-                let status = TokioCommand::new("makepkg")
-                    .args(["-si", "--noconfirm"])
-                    .current_dir(&package_dir)
-                    .status()
-                    .await?;
+    /// fnmatch-style matching with `*` and `?`, as pacman uses for IgnorePkg.
+    fn glob_match(pattern: &str, name: &str) -> bool {
+        let (pattern, name): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+        let (mut p, mut n) = (0, 0);
+        let mut backtrack: Option<(usize, usize)> = None;
 
-                if status.success() {
-                    println!("Dependency {} installed successfully.", dep);
-                } else {
-                    eprintln!("Installation of dependency {} failed.", dep);
+        while n < name.len() {
+            match pattern.get(p) {
+                Some('*') => {
+                    backtrack = Some((p, n));
+                    p += 1;
                 }
+                Some(c) if *c == '?' || *c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match backtrack {
+                    Some((star, matched)) => {
+                        p = star + 1;
+                        n = matched + 1;
+                        backtrack = Some((star, matched + 1));
+                    }
+                    None => return false,
+                },
             }
-        } else {
-            println!("Proceeding without installing missing dependencies.");
         }
 
-        Ok(())
+        pattern[p..].iter().all(|c| *c == '*')
     }
 
-    pub async fn install_package(client: &Client, query: &str) -> Result<()> {
-        // Search for packages
-        let aur_response = aur::search(client, query).await?;
-        let mut aur_packages = aur_response.results.unwrap_or_default();
-        let official_packages = pacman::search(query);
+    /// IgnorePkg entries from pacman.conf.
+    fn pacman_ignores() -> Vec<String> {
+        std::fs::read_to_string("/etc/pacman.conf")
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| {
+                let line = line.split('#').next().unwrap_or_default();
+                let (key, value) = line.split_once('=')?;
+                (key.trim() == "IgnorePkg").then(|| value.split_whitespace().map(|v| v.to_string()).collect::<Vec<_>>())
+            })
+            .flatten()
+            .collect()
+    }
 
-        // Sort AUR packages by votes (ascending - least to most voted)
-        aur_packages.sort_by(|a, b| a.num_votes.cmp(&b.num_votes));
+    /// Reads the config file if it exists. Must be called before the first `get`.
+    pub fn load() -> Result<()> {
+        let path = paths::config_file();
+        let mut config = match std::fs::read_to_string(&path) {
+            Ok(contents) => parse(&contents, &path),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e) => return Err(e.into()),
+        };
+        config.ignore.extend(pacman_ignores());
 
-        // Build combined package list
-        let mut all_packages = Vec::new();
+        CONFIG.set(config).map_err(|_| "Configuration was already loaded".into())
+    }
 
-        // Count official packages
-        let mut official_count = 0;
-        for line in official_packages.iter().filter(|line| !line.starts_with(char::is_whitespace)) {
-            if line.find('[').is_some() {
-                official_count += 1;
+    fn parse(contents: &str, path: &Path) -> Config {
+        let mut config = Config::default();
+
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
             }
-        }
 
-        // Add AUR packages (reversed index order)
-        let total_packages = aur_packages.len() + official_count;
-        for (i, pkg) in aur_packages.iter().enumerate() {
-            let index = total_packages - i;
-            all_packages.push((true, pkg.name.clone(), pkg.version.clone(), index));
-        }
+            let (key, value) = line.split_once('=').unwrap_or((line, ""));
+            let (key, value) = (key.trim(), value.trim());
 
-        // Add official packages
-        let mut curr_index = official_count;
-        for line in official_packages.iter().filter(|line| !line.starts_with(char::is_whitespace)) {
-            if let Some(repo_start) = line.find('[') {
-                let parts: Vec<&str> = line[..repo_start].trim().split_whitespace().collect();
-                if parts.len() >= 1 {
-                    let name = parts[0].to_string();
-                    let version = parts.get(1).map(|&v| v.to_string()).unwrap_or_default();
-                    all_packages.push((false, name, version, curr_index));
-                    curr_index -= 1;
-                }
+            match key {
+                "BuildJobs" => match value.parse::<usize>() {
+                    Ok(jobs) if jobs > 0 => config.build_jobs = jobs,
+                    _ => eprintln!("{}:{}: BuildJobs must be a positive number", path.display(), number + 1),
+                },
+                "GpgKeyserver" => config.gpg_keyserver = value.to_string(),
+                "GpgKeyring" => config.gpg_keyring = Some(PathBuf::from(value)),
+                "IgnorePkg" => config.ignore.extend(value.split_whitespace().map(|v| v.to_string())),
+                "SyncDbMaxAge" => match value.parse::<u64>() {
+                    Ok(hours) => config.sync_db_max_age_hours = hours,
+                    _ => eprintln!("{}:{}: SyncDbMaxAge must be a number of hours", path.display(), number + 1),
+                },
+                "Offline" => match value {
+                    "" | "true" | "yes" => config.offline = true,
+                    _ => eprintln!("{}:{}: Offline must be set without a value, or to true or yes", path.display(), number + 1),
+                },
+                "MetadataSource" => config.metadata_source = value.to_string(),
+                "MetadataMaxAge" => match value.parse::<u64>() {
+                    Ok(hours) => config.metadata_max_age_hours = hours,
+                    _ => eprintln!("{}:{}: MetadataMaxAge must be a number of hours", path.display(), number + 1),
+                },
+                "ConnectTimeout" => match value.parse::<u64>() {
+                    Ok(secs) if secs > 0 => config.connect_timeout = secs,
+                    _ => eprintln!("{}:{}: ConnectTimeout must be a positive number of seconds", path.display(), number + 1),
+                },
+                "ReadTimeout" => match value.parse::<u64>() {
+                    Ok(secs) if secs > 0 => config.read_timeout = secs,
+                    _ => eprintln!("{}:{}: ReadTimeout must be a positive number of seconds", path.display(), number + 1),
+                },
+                "MaxRetries" => match value.parse::<u32>() {
+                    Ok(retries) => config.max_retries = retries,
+                    _ => eprintln!("{}:{}: MaxRetries must be a number", path.display(), number + 1),
+                },
+                "Proxy" => config.proxy = Some(value.to_string()),
+                "NoProxy" => {
+                    let hosts: Vec<&str> = value.split([',', ' ']).filter(|host| !host.is_empty()).collect();
+                    config.no_proxy = Some(hosts.join(","));
+                },
+                "CaBundle" => config.ca_bundles.push(PathBuf::from(value)),
+                "CacheTtl" => match value.parse::<u64>() {
+                    Ok(secs) => config.cache_ttl = secs,
+                    _ => eprintln!("{}:{}: CacheTtl must be a number of seconds", path.display(), number + 1),
+                },
+                _ => eprintln!("{}:{}: unknown option '{}'", path.display(), number + 1, key),
             }
         }
 
-        // Display packages
-        println!("Found {} package(s):", all_packages.len());
-        for (is_aur, name, version, index) in &all_packages {
-            let source = if *is_aur { "AUR" } else { "repo" };
-            let installed = if pacman::is_installed(name) { " (Installed)" } else { "" };
-            println!("{}. {} ({}) [{}]{}", index, name, version, source, installed);
-        }
+        config
+    }
 
-        // Get user selection
-        println!("\nEnter the package number to install (or 'back' to cancel):");
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        let input = input.trim();
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-        if input.eq_ignore_ascii_case("back") {
-            return Ok(());
+        #[test]
+        fn glob_match_literals() {
+            assert!(glob_match("foo", "foo"));
+            assert!(!glob_match("foo", "foobar"));
+            assert!(!glob_match("foobar", "foo"));
+            assert!(!glob_match("", "foo"));
         }
 
-        let selection: usize = input.parse().map_err(|_| "Invalid selection")?;
+        #[test]
+        fn glob_match_wildcards() {
+            assert!(glob_match("foo-*", "foo-git"));
+            assert!(glob_match("foo-*", "foo-"));
+            assert!(!glob_match("foo-*", "foo"));
+            assert!(glob_match("*-git", "bar-git"));
+            assert!(glob_match("*", ""));
+            assert!(glob_match("l?b*", "libfoo"));
+            assert!(!glob_match("l?b", "lb"));
+            // Needs backtracking past the first "-g".
+            assert!(glob_match("*-git", "a-gui-git"));
+            assert!(glob_match("a*b*c", "aXbYbZc"));
+            assert!(!glob_match("a*b*c", "aXbYbZ"));
+        }
 
-        // Find the package with the matching index
-        let selected_package = all_packages.iter()
-            .find(|(_, _, _, idx)| *idx == selection)
-            .ok_or_else(|| format!("Invalid package number: {}", selection))?;
+        #[test]
+        fn parse_offline() {
+            let path = Path::new("aurorus.conf");
+            assert!(parse("Offline", path).offline);
+            assert!(parse("Offline = yes", path).offline);
+            assert!(!parse("Offline = no", path).offline);
+            assert!(!parse("Offline = false", path).offline);
+            assert!(!parse("", path).offline);
+        }
+    }
+}
 
-        let (is_aur, name, _, _) = selected_package;
-        println!("Installing {}...", name);
+mod http {
+    use super::*;
+    use reqwest::{RequestBuilder, Response, StatusCode};
+    use std::time::Duration;
 
-        // Install package
-        if *is_aur {
-            // Handle dependencies for AUR packages
-            handle_dependencies(client, name).await?;
+    /// Longest Retry-After we are willing to wait for.
+    const MAX_RETRY_AFTER: u64 = 120;
 
-            // Clone and build
-            let package_dir = aur::clone_package_repo(name).await?;
-            let status = TokioCommand::new("makepkg")
-                .args(["-si"])
-                .current_dir(&package_dir)
-                .status()
-                .await?;
+    /// The client every request goes through, with the configured timeouts,
+    /// proxy, and extra CA certificates. Without a configured proxy the usual
+    /// proxy environment variables apply.
+    pub fn client() -> Result<Client> {
+        let config = config::get();
+        let mut builder = Client::builder()
+            .connect_timeout(Duration::from_secs(config.connect_timeout))
+            .read_timeout(Duration::from_secs(config.read_timeout));
 
-            if !status.success() {
-                return Err(format!("Failed to install {}", name).into());
+        if let Some(url) = &config.proxy {
+            let proxy = reqwest::Proxy::all(url)?
+                .no_proxy(config.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string));
+            builder = builder.proxy(proxy);
+        }
+        for bundle in &config.ca_bundles {
+            let pem = std::fs::read(bundle)
+                .map_err(|e| format!("Cannot read CA bundle {}: {}", bundle.display(), e))?;
+            for certificate in reqwest::Certificate::from_pem_bundle(&pem)? {
+                builder = builder.add_root_certificate(certificate);
             }
-        } else {
-            // Install from official repos
-            let status = Command::new("sudo")
-                .args(["pacman", "-S", name])
-                .status()?;
+        }
 
-            if !status.success() {
-                return Err(format!("Failed to install {}", name).into());
+        Ok(builder.build()?)
+    }
+
+    /// Environment that points subprocesses which download, like git and
+    /// makepkg, at the configured proxy.
+    pub fn proxy_env() -> Vec<(&'static str, String)> {
+        let config = config::get();
+        let mut env = Vec::new();
+        if let Some(proxy) = &config.proxy {
+            for key in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+                env.push((key, proxy.clone()));
             }
         }
-
-        println!("Package {} installed successfully.", name);
-        Ok(())
+        if let Some(no_proxy) = &config.no_proxy {
+            for key in ["no_proxy", "NO_PROXY"] {
+                env.push((key, no_proxy.clone()));
+            }
+        }
+        env
     }
 
-    pub async fn update_packages(client: &Client) -> Result<()> {
-        // Get installed AUR packages
-        let packages = pacman::get_installed_aur_packages()?;
+    fn is_transient(status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+    }
 
-        if !packages.is_empty() {
-            println!("Checking {} AUR package(s)...", packages.len());
+    /// Retry-After in seconds. HTTP dates aren't used by the AUR.
+    fn retry_after(resp: &Response) -> Option<u64> {
+        resp.headers()
+            .get(reqwest::header::RETRY_AFTER)?
+            .to_str().ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
 
-            // Create chunks for bulk RPC requests
-            let chunk_size = 50; // AUR allows up to 50 packages per request
-            let packages_chunks: Vec<Vec<String>> = packages
-                .chunks(chunk_size)
-                .map(|chunk| chunk.iter().map(|(name, _)| name.clone()).collect())
-                .collect();
+    /// Sends `request`, retrying timeouts, connection errors, 429 and 5xx
+    /// responses up to `MaxRetries` times with exponential backoff. A
+    /// Retry-After header sets the wait instead. The last response is
+    /// returned as is, so callers still check its status.
+    pub async fn send(request: RequestBuilder) -> Result<Response> {
+        let retries = config::get().max_retries;
+        let mut attempt = 0;
 
-            // Process chunks in parallel
-            let mut updates_available = Vec::new();
+        loop {
+            let Some(this_try) = request.try_clone() else {
+                // Streaming bodies can't be sent twice.
+                return Ok(request.send().await?);
+            };
+            let backoff = 1u64 << attempt.min(6);
 
-            let results = stream::iter(packages_chunks)
-                .map(|chunk| {
-                    let client = client.clone();
-                    async move {
-                        let names = chunk.join("&arg[]=");
-                        let url = format!(
-                            "https://aur.archlinux.org/rpc/?v=5&type=info&arg[]={}",
-                            names
-                        );
-                        client.get(&url).send().await?.json::<AurResponse>().await
+            let wait = match this_try.send().await {
+                Ok(resp) if attempt < retries && is_transient(resp.status()) => {
+                    let wait = retry_after(&resp).unwrap_or(backoff);
+                    if wait > MAX_RETRY_AFTER {
+                        return Err(format!("{} asked to retry after {}s, giving up", resp.url(), wait).into());
                     }
-                })
-                .buffer_unordered(4)
-                .collect::<Vec<_>>()
-                .await;
+                    status!("{} returned {}, retrying in {}s...", resp.url(), resp.status(), wait);
+                    wait
+                }
+                Err(e) if attempt < retries && (e.is_timeout() || e.is_connect()) => {
+                    status!("Request failed ({}), retrying in {}s...", e, backoff);
+                    backoff
+                }
+                result => return Ok(result?),
+            };
+
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            attempt += 1;
+        }
+    }
+}
+
+mod responses {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{LazyLock, Mutex};
+
+    static REFRESH: AtomicBool = AtomicBool::new(false);
+    static MEMORY: LazyLock<Mutex<HashMap<String, (u64, String)>>> = LazyLock::new(Default::default);
+
+    /// Ignores cached responses for the current command; fresh ones are still stored.
+    pub fn set_refresh(refresh: bool) {
+        REFRESH.store(refresh, Ordering::Relaxed);
+    }
+
+    fn file(key: &str) -> PathBuf {
+        let hash = ring::digest::digest(&ring::digest::SHA256, key.as_bytes());
+        let name: String = hash.as_ref().iter().map(|byte| format!("{:02x}", byte)).collect();
+        paths::response_cache_dir().join(name)
+    }
+
+    /// The cached response for `key` if it is younger than `CacheTtl`.
+    pub fn get(key: &str) -> Option<String> {
+        let ttl = config::get().cache_ttl;
+        if ttl == 0 || REFRESH.load(Ordering::Relaxed) {
+            return None;
+        }
+        let fresh = |time: u64| clock::unix_now().saturating_sub(time) <= ttl;
+
+        let mut memory = MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((time, body)) = memory.get(key) {
+            return fresh(*time).then(|| body.clone());
+        }
+
+        // Another aurorus process may have fetched it recently.
+        let path = file(key);
+        let time = std::fs::metadata(&path).ok()?
+            .modified().ok()?
+            .duration_since(std::time::UNIX_EPOCH).ok()?
+            .as_secs();
+        if !fresh(time) {
+            return None;
+        }
+        let body = std::fs::read_to_string(&path).ok()?;
+        memory.insert(key.to_string(), (time, body.clone()));
+        Some(body)
+    }
+
+    /// Stores a response in memory and, best effort, on disk.
+    pub fn put(key: &str, body: &str) {
+        if config::get().cache_ttl == 0 {
+            return;
+        }
+        MEMORY.lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key.to_string(), (clock::unix_now(), body.to_string()));
+        if std::fs::create_dir_all(paths::response_cache_dir()).is_ok() {
+            let _ = std::fs::write(file(key), body);
+        }
+    }
+}
+
+mod aur {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    const RPC_URL: &str = "https://aur.archlinux.org/rpc/";
+
+    /// GETs `url` as text, answering from the response cache when possible.
+    async fn get_cached(client: &Client, url: reqwest::Url) -> Result<String> {
+        if let Some(body) = responses::get(url.as_str()) {
+            return Ok(body);
+        }
+
+        let resp = http::send(client.get(url.clone())).await?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP error: {}", resp.status()).into());
+        }
+        let body = resp.text().await?;
+        responses::put(url.as_str(), &body);
+        Ok(body)
+    }
+
+    /// Calls the RPC with the given query parameters.
+    async fn rpc(client: &Client, query: &[(&str, &str)]) -> Result<AurResponse> {
+        let url = reqwest::Url::parse_with_params(RPC_URL, query).map_err(|e| e.to_string())?;
+        let body = get_cached(client, url).await?;
+        serde_json::from_str(&body).map_err(|e| format!("Invalid response from the AUR: {}", e).into())
+    }
+
+    static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+    /// Answers searches and lookups from the metadata dump instead of the RPC.
+    pub fn set_offline(offline: bool) {
+        OFFLINE.store(offline, Ordering::Relaxed);
+    }
+
+    pub fn is_offline() -> bool {
+        OFFLINE.load(Ordering::Relaxed)
+    }
+
+    pub async fn search(client: &Client, query: &str) -> Result<AurResponse> {
+        if is_offline() {
+            let results = dump::search(client, query).await?;
+            return Ok(AurResponse {
+                version: 5,
+                resp_type: "search".to_string(),
+                resultcount: results.len() as u32,
+                results: Some(results),
+            });
+        }
+
+        rpc(client, &[("v", "5"), ("type", "search"), ("arg", query)]).await
+    }
+
+    /// Searches by a specific field, e.g. `provides` or `replaces`.
+    pub async fn search_by(client: &Client, field: &str, arg: &str) -> Result<Vec<AurPackage>> {
+        if is_offline() {
+            return dump::search_by(client, field, arg).await;
+        }
+
+        let response = rpc(client, &[("v", "5"), ("type", "search"), ("by", field), ("arg", arg)]).await?;
+        Ok(response.results.unwrap_or_default())
+    }
+
+    /// Looks up packages by exact name, 50 per request as the RPC allows.
+    pub async fn info(client: &Client, names: &[String]) -> Result<Vec<AurPackage>> {
+        if is_offline() {
+            return dump::info(client, names).await;
+        }
+
+        let mut packages = Vec::new();
+
+        for chunk in names.chunks(50) {
+            let mut query = vec![("v", "5"), ("type", "info")];
+            query.extend(chunk.iter().map(|name| ("arg[]", name.as_str())));
+
+            let response = rpc(client, &query).await?;
+            packages.extend(response.results.unwrap_or_default());
+        }
+
+        Ok(packages)
+    }
+
+    /// The parts of a .SRCINFO that matter for dependency resolution. Fields of
+    /// the pkgbase and of every split package are merged, and architecture
+    /// specific entries (e.g. `depends_x86_64`) are included.
+    #[derive(Debug, Default, Clone)]
+    pub struct Srcinfo {
+        pub pkgbase: String,
+        pub pkgnames: Vec<String>,
+        pub depends: Vec<String>,
+        pub makedepends: Vec<String>,
+        pub checkdepends: Vec<String>,
+        pub provides: Vec<String>,
+        pub conflicts: Vec<String>,
+        pub replaces: Vec<String>,
+        pub validpgpkeys: Vec<String>,
+        /// `source` arrays keyed by architecture suffix ("" for `source`).
+        pub sources: BTreeMap<String, Vec<String>>,
+        /// Checksum arrays keyed by their full key, e.g. `sha256sums_x86_64`.
+        pub checksums: BTreeMap<String, Vec<String>>,
+    }
+
+    impl Srcinfo {
+        /// The same fields as an RPC info lookup reports them for one package.
+        pub fn from_info(pkg: &AurPackage) -> Srcinfo {
+            let list = |field: &Option<Vec<String>>| field.clone().unwrap_or_default();
+            Srcinfo {
+                pkgbase: pkg.package_base.clone().unwrap_or_else(|| pkg.name.clone()),
+                pkgnames: vec![pkg.name.clone()],
+                depends: list(&pkg.depends),
+                makedepends: list(&pkg.make_depends),
+                checkdepends: list(&pkg.check_depends),
+                provides: list(&pkg.provides),
+                conflicts: list(&pkg.conflicts),
+                replaces: list(&pkg.replaces),
+                ..Default::default()
+            }
+        }
+
+        /// Adds the dependency fields of another package of the same pkgbase.
+        pub fn merge(&mut self, other: Srcinfo) {
+            let fields = [
+                (&mut self.pkgnames, other.pkgnames),
+                (&mut self.depends, other.depends),
+                (&mut self.makedepends, other.makedepends),
+                (&mut self.checkdepends, other.checkdepends),
+                (&mut self.provides, other.provides),
+                (&mut self.conflicts, other.conflicts),
+                (&mut self.replaces, other.replaces),
+            ];
+            for (list, more) in fields {
+                for value in more {
+                    if !list.contains(&value) {
+                        list.push(value);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn parse_srcinfo(srcinfo: &str) -> Srcinfo {
+        let mut info = Srcinfo::default();
+
+        for line in srcinfo.lines() {
+            let Some((key, value)) = line.trim().split_once('=') else { continue };
+            let (key, value) = (key.trim(), value.trim().to_string());
+            if value.is_empty() {
+                continue;
+            }
+
+            let (field, arch) = key.split_once('_').unwrap_or((key, ""));
+            if field == "source" {
+                info.sources.entry(arch.to_string()).or_default().push(value);
+                continue;
+            }
+            if field.ends_with("sums") {
+                info.checksums.entry(key.to_string()).or_default().push(value);
+                continue;
+            }
+
+            let list = match field {
+                "pkgbase" => {
+                    info.pkgbase = value;
+                    continue;
+                }
+                "pkgname" => &mut info.pkgnames,
+                "depends" => &mut info.depends,
+                "makedepends" => &mut info.makedepends,
+                "checkdepends" => &mut info.checkdepends,
+                "provides" => &mut info.provides,
+                "conflicts" => &mut info.conflicts,
+                "replaces" => &mut info.replaces,
+                "validpgpkeys" => &mut info.validpgpkeys,
+                _ => continue,
+            };
+            if !list.contains(&value) {
+                list.push(value);
+            }
+        }
+
+        info
+    }
+
+    /// Strips the version constraint from a dependency, e.g. `foo>=1.2` -> `foo`.
+    pub fn dep_name(dep: &str) -> &str {
+        dep.split(['<', '>', '=']).next().unwrap_or(dep).trim()
+    }
+
+    pub async fn clone_package_repo(package: &str) -> Result<String> {
+        let repo_url = format!("https://aur.archlinux.org/{}.git", package);
+        let cache_dir = paths::cache_dir();
+        let dest = format!("{}/{}", cache_dir, package);
+
+        if !Path::new(&cache_dir).exists() {
+            fs::create_dir_all(&cache_dir).await?;
+        }
+
+        if Path::new(&dest).exists() {
+            println!("Directory {} already exists. Removing...", dest);
+            fs::remove_dir_all(&dest).await?;
+        }
+
+        println!("Cloning {} into {} ...", repo_url, dest);
+        let status = Command::new("git")
+            .args(["clone", &repo_url, &dest])
+            .envs(http::proxy_env())
+            .status()?;
+
+        if !status.success() {
+            return Err(format!("Failed to clone repository for {}.", package).into());
+        }
+
+        Ok(dest)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SRCINFO: &str = "\
+pkgbase = foo
+\tpkgver = 1.0
+\tpkgrel = 1
+\tmakedepends = git
+\tmakedepends = cmake>=3.20
+\tcheckdepends = python-pytest
+\tdepends = glibc
+\tdepends_x86_64 = lib32-glibc
+\tsource = foo-1.0.tar.gz::https://example.com/foo-1.0.tar.gz
+\tsource_x86_64 = https://example.com/foo-x86_64.patch
+\tvalidpgpkeys = ABCDEF0123456789
+\tsha256sums = 0123
+\tsha256sums_x86_64 = SKIP
+
+pkgname = foo
+\tdepends = glibc
+\tprovides = foo-bin=1.0
+\tconflicts = foo-git
+
+pkgname = foo-docs
+\tdepends =
+\treplaces = foo-doc
+";
+
+        #[test]
+        fn parse_srcinfo_merges_packages() {
+            let info = parse_srcinfo(SRCINFO);
+            assert_eq!(info.pkgbase, "foo");
+            assert_eq!(info.pkgnames, ["foo", "foo-docs"]);
+            assert_eq!(info.depends, ["glibc", "lib32-glibc"]);
+            assert_eq!(info.makedepends, ["git", "cmake>=3.20"]);
+            assert_eq!(info.checkdepends, ["python-pytest"]);
+            assert_eq!(info.provides, ["foo-bin=1.0"]);
+            assert_eq!(info.conflicts, ["foo-git"]);
+            assert_eq!(info.replaces, ["foo-doc"]);
+            assert_eq!(info.validpgpkeys, ["ABCDEF0123456789"]);
+        }
+
+        #[test]
+        fn parse_srcinfo_keeps_sources_and_checksums_by_arch() {
+            let info = parse_srcinfo(SRCINFO);
+            assert_eq!(info.sources[""], ["foo-1.0.tar.gz::https://example.com/foo-1.0.tar.gz"]);
+            assert_eq!(info.sources["x86_64"], ["https://example.com/foo-x86_64.patch"]);
+            assert_eq!(info.checksums["sha256sums"], ["0123"]);
+            assert_eq!(info.checksums["sha256sums_x86_64"], ["SKIP"]);
+        }
+
+        #[test]
+        fn dep_name_strips_constraints() {
+            assert_eq!(dep_name("cmake>=3.20"), "cmake");
+            assert_eq!(dep_name("foo-bin=1.0"), "foo-bin");
+            assert_eq!(dep_name("bar<2"), "bar");
+            assert_eq!(dep_name("baz"), "baz");
+        }
+    }
+}
+
+mod dump {
+    use super::*;
+    use io::Read;
+    use tokio::sync::OnceCell;
+
+    static PACKAGES: OnceCell<Vec<AurPackage>> = OnceCell::const_new();
+
+    /// Every package in the AUR metadata dump, loaded on first use.
+    async fn packages(client: &Client) -> Result<&'static [AurPackage]> {
+        PACKAGES.get_or_try_init(|| load(client)).await.map(|packages| packages.as_slice())
+    }
+
+    fn is_fresh(path: &Path) -> bool {
+        let max_age = std::time::Duration::from_secs(config::get().metadata_max_age_hours * 3600);
+        std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age <= max_age))
+    }
+
+    async fn download(client: &Client, url: &str, path: &Path) -> Result<()> {
+        status!("Downloading the AUR metadata dump from {}...", url);
+        let resp = http::send(client.get(url)).await?;
+        if !resp.status().is_success() {
+            return Err(format!("HTTP error: {}", resp.status()).into());
+        }
+        let bytes = resp.bytes().await?;
+
+        fs::create_dir_all(paths::cache_dir()).await?;
+        let partial = path.with_extension("part");
+        fs::write(&partial, &bytes).await?;
+        fs::rename(&partial, path).await?;
+        Ok(())
+    }
+
+    /// Reads the dump from `MetadataSource`. A URL is downloaded to the cache
+    /// when the cached copy is older than `MetadataMaxAge`; a stale copy is
+    /// still used if the download fails.
+    async fn load(client: &Client) -> Result<Vec<AurPackage>> {
+        let source = &config::get().metadata_source;
+        let path = if source.starts_with("https://") || source.starts_with("http://") {
+            let path = paths::metadata_file();
+            if !is_fresh(&path) {
+                match download(client, source, &path).await {
+                    Ok(()) => {}
+                    Err(e) if path.exists() => eprintln!("Warning: using the cached AUR metadata dump, refreshing it failed: {}", e),
+                    Err(e) => return Err(e),
+                }
+            }
+            path
+        } else {
+            PathBuf::from(source)
+        };
+
+        let bytes = fs::read(&path).await?;
+        let mut json = Vec::new();
+        // The dump is gzipped, but an already extracted copy works too.
+        let contents = if bytes.starts_with(&[0x1f, 0x8b]) {
+            flate2::read::GzDecoder::new(bytes.as_slice()).read_to_end(&mut json)?;
+            &json
+        } else {
+            &bytes
+        };
+        serde_json::from_slice(contents)
+            .map_err(|e| format!("Invalid AUR metadata dump {}: {}", path.display(), e).into())
+    }
+
+    /// Like the RPC's default search: the query in the name or description.
+    pub async fn search(client: &Client, query: &str) -> Result<Vec<AurPackage>> {
+        let query = query.to_lowercase();
+        Ok(packages(client).await?
+            .iter()
+            .filter(|pkg| {
+                pkg.name.to_lowercase().contains(&query)
+                    || pkg.description.as_ref().is_some_and(|desc| desc.to_lowercase().contains(&query))
+            })
+            .cloned()
+            .collect())
+    }
+
+    pub async fn search_by(client: &Client, field: &str, arg: &str) -> Result<Vec<AurPackage>> {
+        let matches = |list: &Option<Vec<String>>| {
+            list.iter().flatten().any(|entry| aur::dep_name(entry) == arg)
+        };
+        let packages = packages(client).await?;
+        let found = match field {
+            "name" => packages.iter().filter(|pkg| pkg.name == arg).cloned().collect(),
+            "provides" => packages.iter().filter(|pkg| matches(&pkg.provides)).cloned().collect(),
+            "replaces" => packages.iter().filter(|pkg| matches(&pkg.replaces)).cloned().collect(),
+            _ => return Err(format!("Searching by {} is not supported offline", field).into()),
+        };
+        Ok(found)
+    }
+
+    pub async fn info(client: &Client, names: &[String]) -> Result<Vec<AurPackage>> {
+        Ok(packages(client).await?
+            .iter()
+            .filter(|pkg| names.contains(&pkg.name))
+            .cloned()
+            .collect())
+    }
+}
+
+mod pacman {
+    use super::*;
+
+    pub fn search(query: &str) -> Vec<String> {
+        Command::new("pacman")
+            .arg("-Ss")
+            .arg(query)
+            .output()
+            .map(|output| {
+                String::from_utf8_lossy(&output.stdout)
+                    .lines()
+                    .map(|line| line.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// `pacman -Ss` results for `query`.
+    pub fn search_repo(query: &str) -> Vec<RepoPackage> {
+        let mut packages: Vec<RepoPackage> = Vec::new();
+        for line in search(query) {
+            if line.starts_with(char::is_whitespace) {
+                if let Some(pkg) = packages.last_mut() {
+                    pkg.description = Some(line.trim().to_string());
+                }
+                continue;
+            }
+            let mut parts = line.split_whitespace();
+            if let (Some((repo, name)), Some(version)) = (parts.next().and_then(|id| id.split_once('/')), parts.next()) {
+                packages.push(RepoPackage {
+                    repo: repo.to_string(),
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    description: None,
+                    url: None,
+                });
+            }
+        }
+        packages
+    }
+
+    /// `pacman -Si` for each of `names` found in the sync repositories.
+    pub fn repo_info(names: &[String]) -> Result<Vec<RepoPackage>> {
+        if names.is_empty() {
+            return Ok(Vec::new());
+        }
+        let output = Command::new("pacman").arg("-Si").args(names).env("LC_ALL", "C").output()?;
+
+        let mut packages = Vec::new();
+        for block in String::from_utf8_lossy(&output.stdout).split("\n\n") {
+            let field = |key: &str| {
+                block.lines()
+                    .filter_map(|line| line.split_once(" : "))
+                    .find(|(k, _)| k.trim() == key)
+                    .map(|(_, v)| v.trim().to_string())
+                    .filter(|v| v != "None")
+            };
+            if let (Some(repo), Some(name), Some(version)) = (field("Repository"), field("Name"), field("Version")) {
+                packages.push(RepoPackage { repo, name, version, description: field("Description"), url: field("URL") });
+            }
+        }
+        Ok(packages)
+    }
+
+    pub fn is_installed(package: &str) -> bool {
+        Command::new("pacman")
+            .args(["-Q", package])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Returns the dependencies in `deps` that no installed package satisfies.
+    pub fn unsatisfied(deps: &[String]) -> Result<Vec<String>> {
+        if deps.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let output = Command::new("pacman").arg("-T").args(deps).output()?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|line| line.to_string())
+            .collect())
+    }
+
+    /// Whether a sync repository has a package with exactly this name. Accepts
+    /// `repo/name` as well.
+    pub fn in_sync_db(target: &str) -> bool {
+        Command::new("pacman")
+            .args(["-Si", target])
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// Whether `dep` can be installed from the sync repositories, either by
+    /// name or through a provider.
+    pub fn in_repos(dep: &str) -> bool {
+        repo_provider(dep).is_some()
+    }
+
+    /// The sync package pacman would install for `dep`.
+    pub fn repo_provider(dep: &str) -> Option<String> {
+        let output = Command::new("pacman")
+            .args(["-Sp", "--noconfirm", "--print-format", "%r/%n", dep])
+            .stdin(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8_lossy(&output.stdout).lines().next().map(|line| line.to_string())
+    }
+
+    /// An installed package as described by `pacman -Qi`.
+    #[derive(Debug, Clone, Default)]
+    pub struct LocalPackage {
+        pub name: String,
+        pub version: String,
+        pub provides: Vec<String>,
+        pub conflicts: Vec<String>,
+        pub depends: Vec<String>,
+        pub required_by: Vec<String>,
+        pub optional_for: Vec<String>,
+        /// Installed as a dependency rather than explicitly.
+        pub as_dep: bool,
+        /// Installed size in bytes.
+        pub size: u64,
+    }
+
+    /// Parses a size as printed by pacman, e.g. "1.50 MiB".
+    fn parse_size(value: &str) -> u64 {
+        let (number, unit) = value.split_once(' ').unwrap_or((value, "B"));
+        let scale: u64 = match unit {
+            "KiB" => 1 << 10,
+            "MiB" => 1 << 20,
+            "GiB" => 1 << 30,
+            "TiB" => 1 << 40,
+            _ => 1,
+        };
+        number.parse::<f64>().map_or(0, |n| (n * scale as f64) as u64)
+    }
+
+    /// Every installed package from the local database.
+    pub fn local_packages() -> Result<Vec<LocalPackage>> {
+        let output = Command::new("pacman").arg("-Qi").env("LC_ALL", "C").output()?;
+        let mut packages = Vec::new();
+        let mut current = LocalPackage::default();
+        let mut key = String::new();
+
+        for line in String::from_utf8_lossy(&output.stdout).lines() {
+            if line.trim().is_empty() {
+                if !current.name.is_empty() {
+                    packages.push(std::mem::take(&mut current));
+                }
+                continue;
+            }
+
+            // Long lists continue on indented lines without a key.
+            let value = match line.split_once(" : ") {
+                Some((k, v)) if !line.starts_with(' ') => {
+                    key = k.trim().to_string();
+                    v.trim()
+                }
+                _ => line.trim(),
+            };
+            let list = || -> Vec<String> {
+                if value == "None" {
+                    Vec::new()
+                } else {
+                    value.split_whitespace().map(|item| item.to_string()).collect()
+                }
+            };
+
+            match key.as_str() {
+                "Name" => current.name = value.to_string(),
+                "Version" => current.version = value.to_string(),
+                "Provides" => current.provides.extend(list()),
+                "Conflicts With" => current.conflicts.extend(list()),
+                "Depends On" => current.depends.extend(list()),
+                "Required By" => current.required_by.extend(list()),
+                "Optional For" => current.optional_for.extend(list()),
+                "Install Reason" => current.as_dep = value.starts_with("Installed as a dependency"),
+                "Installed Size" => current.size = parse_size(value),
+                _ => {}
+            }
+        }
+        if !current.name.is_empty() {
+            packages.push(current);
+        }
+
+        Ok(packages)
+    }
+
+    /// Packages installed as dependencies that nothing needs anymore, not even
+    /// optionally, including ones that only become unneeded once the others
+    /// are gone.
+    pub fn unneeded(packages: &[LocalPackage]) -> Vec<&LocalPackage> {
+        let mut removed: BTreeSet<&str> = BTreeSet::new();
+        loop {
+            let next: Vec<&str> = packages.iter()
+                .filter(|pkg| pkg.as_dep && !removed.contains(pkg.name.as_str()))
+                .filter(|pkg| pkg.required_by.iter().chain(&pkg.optional_for).all(|by| removed.contains(by.as_str())))
+                .map(|pkg| pkg.name.as_str())
+                .collect();
+            if next.is_empty() {
+                break;
+            }
+            removed.extend(next);
+        }
+        packages.iter().filter(|pkg| removed.contains(pkg.name.as_str())).collect()
+    }
+
+    /// Installed packages outside `removing` that would lose a dependency,
+    /// directly or through provides, as (dependent, package it needs). A
+    /// dependency another remaining package satisfies doesn't count.
+    pub fn broken_by(packages: &[LocalPackage], removing: &BTreeSet<String>) -> Vec<(String, String)> {
+        let satisfies = |pkg: &LocalPackage, name: &str| {
+            pkg.name == name || pkg.provides.iter().any(|provided| aur::dep_name(provided) == name)
+        };
+
+        packages.iter()
+            .filter(|pkg| !removing.contains(&pkg.name))
+            .filter_map(|pkg| {
+                pkg.depends.iter().find_map(|dep| {
+                    let name = aur::dep_name(dep);
+                    let satisfiers: Vec<&LocalPackage> = packages.iter().filter(|other| satisfies(other, name)).collect();
+                    let first = satisfiers.first()?;
+                    satisfiers.iter().all(|other| removing.contains(&other.name))
+                        .then(|| (pkg.name.clone(), first.name.clone()))
+                })
+            })
+            .collect()
+    }
+
+    /// What `pacman -Rns` would remove for `targets`, including dependencies
+    /// that become unneeded.
+    pub fn removal_targets(targets: &[String]) -> Result<Vec<String>> {
+        let output = Command::new("pacman")
+            .args(["-Rnsp", "--print-format", "%n"])
+            .args(targets)
+            .output()?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).lines().map(|line| line.to_string()).collect())
+    }
+
+    /// Upgrades available from the sync repositories as (name, old, new).
+    /// Uses checkupdates from pacman-contrib when available so the answer
+    /// doesn't depend on when the databases were last synced.
+    pub fn pending_upgrades() -> Result<Vec<(String, String, String)>> {
+        let output = if Path::new("/usr/bin/checkupdates").exists() {
+            Command::new("checkupdates").output()?
+        } else {
+            Command::new("pacman").arg("-Qu").output()?
+        };
+
+        Ok(parse_upgrades(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Parses `name old -> new` lines, skipping packages held back by IgnorePkg.
+    fn parse_upgrades(output: &str) -> Vec<(String, String, String)> {
+        output.lines()
+            .filter(|line| !line.ends_with("[ignored]"))
+            .filter_map(|line| {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                match parts.as_slice() {
+                    [name, old, "->", new, ..] => Some((name.to_string(), old.to_string(), new.to_string())),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Time since the sync databases were last refreshed.
+    pub fn sync_db_age() -> Option<std::time::Duration> {
+        std::fs::read_dir("/var/lib/pacman/sync").ok()?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "db"))
+            .filter_map(|entry| entry.metadata().ok()?.modified().ok())
+            .max()?
+            .elapsed()
+            .ok()
+    }
+
+    /// Makes sure installing with plain `-S` won't cause a partial upgrade:
+    /// the sync databases must be recent and the system upgraded to them.
+    /// Otherwise offers a full `-Syu` first and fails if that is declined.
+    pub fn ensure_synced() -> Result<()> {
+        let max_age = std::time::Duration::from_secs(config::get().sync_db_max_age_hours * 3600);
+        let problem = match sync_db_age() {
+            Some(age) if age > max_age => Some(format!("The sync databases are {} hours old", age.as_secs() / 3600)),
+            None => Some("The sync databases have never been synced".to_string()),
+            _ => {
+                let pending = Command::new("pacman").arg("-Qu").output()?;
+                // Packages held back by IgnorePkg stay behind on purpose.
+                let count = String::from_utf8_lossy(&pending.stdout)
+                    .lines()
+                    .filter(|line| !line.ends_with("[ignored]"))
+                    .filter(|line| line.split_whitespace().next().is_some_and(|name| !config::get().is_ignored(name)))
+                    .count();
+                (count > 0).then(|| format!("{} package(s) are not upgraded to the sync databases", count))
+            }
+        };
+
+        let Some(problem) = problem else {
+            return Ok(());
+        };
+        println!("\n{}. Installing with 'pacman -S' alone could leave a partially upgraded system.", problem);
+        if !confirm("Run 'pacman -Syu' first?", true)? {
+            return Err("Not installing repository packages against out-of-date sync databases".into());
+        }
+        if !Command::new("sudo").args(["pacman", "-Syu"]).status()?.success() {
+            return Err("Failed to update official packages".into());
+        }
+        Ok(())
+    }
+
+    /// What `pacman -S` would install for `targets`, as (name, version).
+    pub fn sync_targets(targets: &[String]) -> Result<Vec<(String, String)>> {
+        let output = Command::new("pacman")
+            .args(["-Sp", "--print-format", "%n %v"])
+            .args(targets)
+            .output()?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string().into());
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect())
+    }
+
+    /// Every installed package with its version, from `pacman -Q`.
+    pub fn installed_versions() -> Result<BTreeMap<String, String>> {
+        let output = Command::new("pacman").arg("-Q").output()?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(name, version)| (name.to_string(), version.to_string()))
+            .collect())
+    }
+
+    pub fn get_installed_aur_packages() -> Result<Vec<(String, String)>> {
+        let output = Command::new("pacman").args(["-Qm"]).output()?;
+        let installed = String::from_utf8_lossy(&output.stdout);
+
+        let packages = installed
+            .lines()
+            .filter_map(|line| {
+                let parts: Vec<&str> = line.split_whitespace().collect();
+                if parts.len() >= 2 {
+                    Some((parts[0].to_string(), parts[1].to_string()))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        Ok(packages)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn package(name: &str, as_dep: bool, required_by: &[&str], optional_for: &[&str]) -> LocalPackage {
+            LocalPackage {
+                name: name.to_string(),
+                as_dep,
+                required_by: required_by.iter().map(|s| s.to_string()).collect(),
+                optional_for: optional_for.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }
+        }
+
+        fn names(packages: Vec<&LocalPackage>) -> Vec<&str> {
+            packages.iter().map(|pkg| pkg.name.as_str()).collect()
+        }
+
+        #[test]
+        fn unneeded_follows_chains() {
+            let packages = [
+                package("app", false, &[], &[]),
+                package("libapp", true, &["app"], &[]),
+                package("orphan", true, &[], &[]),
+                package("orphan-dep", true, &["orphan"], &[]),
+                package("orphan-dep-dep", true, &["orphan-dep"], &[]),
+            ];
+            assert_eq!(names(unneeded(&packages)), ["orphan", "orphan-dep", "orphan-dep-dep"]);
+        }
+
+        #[test]
+        fn unneeded_keeps_explicit_and_optional_packages() {
+            let packages = [
+                package("explicit", false, &[], &[]),
+                package("optional", true, &[], &["explicit"]),
+                package("shared", true, &["explicit", "orphan"], &[]),
+                package("orphan", true, &[], &[]),
+            ];
+            assert_eq!(names(unneeded(&packages)), ["orphan"]);
+        }
+
+        fn depending(name: &str, depends: &[&str], provides: &[&str]) -> LocalPackage {
+            LocalPackage {
+                name: name.to_string(),
+                depends: depends.iter().map(|s| s.to_string()).collect(),
+                provides: provides.iter().map(|s| s.to_string()).collect(),
+                ..Default::default()
+            }
+        }
+
+        fn removing(names: &[&str]) -> BTreeSet<String> {
+            names.iter().map(|s| s.to_string()).collect()
+        }
+
+        #[test]
+        fn broken_by_direct_and_provided_dependencies() {
+            let packages = [
+                depending("lib", &[], &["libfoo.so=1-64", "libfoo=1.0"]),
+                depending("direct", &["lib>=1"], &[]),
+                depending("via-provides", &["libfoo.so=1-64"], &[]),
+                depending("unrelated", &["glibc"], &[]),
+                depending("glibc", &[], &[]),
+            ];
+            assert_eq!(broken_by(&packages, &removing(&["lib"])), [
+                ("direct".to_string(), "lib".to_string()),
+                ("via-provides".to_string(), "lib".to_string()),
+            ]);
+        }
+
+        #[test]
+        fn broken_by_ignores_dependencies_another_package_satisfies() {
+            let packages = [
+                depending("jre-a", &[], &["java-runtime"]),
+                depending("jre-b", &[], &["java-runtime"]),
+                depending("app", &["java-runtime"], &[]),
+            ];
+            assert!(broken_by(&packages, &removing(&["jre-a"])).is_empty());
+            assert_eq!(broken_by(&packages, &removing(&["jre-a", "jre-b"])), [("app".to_string(), "jre-a".to_string())]);
+        }
+
+        #[test]
+        fn broken_by_skips_packages_being_removed() {
+            let packages = [
+                depending("lib", &[], &[]),
+                depending("app", &["lib"], &[]),
+            ];
+            assert!(broken_by(&packages, &removing(&["lib", "app"])).is_empty());
+        }
+
+        #[test]
+        fn parse_upgrades_skips_ignored_packages() {
+            let output = "linux 6.9.1.arch1-1 -> 6.9.2.arch1-1\n\
+                          firefox 126.0-1 -> 126.0.1-1 [ignored]\n\
+                          \n\
+                          :: garbage\n";
+            assert_eq!(parse_upgrades(output), [(
+                "linux".to_string(),
+                "6.9.1.arch1-1".to_string(),
+                "6.9.2.arch1-1".to_string(),
+            )]);
+        }
+    }
+}
+
+mod plan {
+    use super::*;
+    use serde::Serialize;
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+    #[derive(Debug, Clone, Serialize)]
+    pub struct PlanPackage {
+        pub name: String,
+        /// Version to be installed.
+        pub version: String,
+        /// Currently installed version, if any.
+        pub installed: Option<String>,
+    }
+
+    /// One AUR pkgbase to build.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct PlanBase {
+        pub pkgbase: String,
+        /// Packages of this base to install.
+        pub packages: Vec<PlanPackage>,
+        /// Pulled in as a dependency rather than requested by the user.
+        pub as_deps: bool,
+        /// Only needed to build other packages, not at runtime.
+        pub make_only: bool,
+        /// Other pkgbases in the plan that have to be built first.
+        pub needs: Vec<String>,
+    }
+
+    /// An installed package that has to go before a plan package can be installed.
+    #[derive(Debug, Clone, Serialize)]
+    pub struct Conflict {
+        pub pkgbase: String,
+        pub package: String,
+        pub installed: String,
+        pub reason: String,
+        /// Replaced without conflicting, so `pacman -U` leaves it installed.
+        #[serde(skip)]
+        pub replaced_only: bool,
+    }
+
+    #[derive(Debug, Default, Serialize)]
+    pub struct Plan {
+        /// Packages requested from the sync repositories.
+        pub repo_targets: Vec<PlanPackage>,
+        /// Dependencies to install from the sync repositories before building.
+        pub repo_deps: Vec<String>,
+        /// Pending upgrades from the sync repositories.
+        pub repo_upgrades: Vec<PlanPackage>,
+        /// AUR pkgbases grouped so that each layer only needs earlier layers.
+        pub layers: Vec<Vec<PlanBase>>,
+        pub conflicts: Vec<Conflict>,
+        /// Let `pacman -U` remove conflicting packages when installing what replaces them.
+        #[serde(skip)]
+        pub remove_conflicts: bool,
+    }
+
+    impl Plan {
+        pub fn bases(&self) -> impl Iterator<Item = &PlanBase> {
+            self.layers.iter().flatten()
+        }
+    }
+
+    struct Node {
+        srcinfo: aur::Srcinfo,
+        packages: BTreeMap<String, String>,
+        as_deps: bool,
+    }
+
+    /// Sorts the dependencies of a newly planned package into repository
+    /// dependencies and AUR packages still to look up. Dependencies that only
+    /// `foreign` packages satisfy are looked up too and kept in `host_satisfied`,
+    /// since a clean chroot only has what the repositories provide.
+    fn queue_deps(
+        info: &aur::Srcinfo,
+        by_name: &HashMap<String, String>,
+        foreign: &[pacman::LocalPackage],
+        repo_deps: &mut BTreeSet<String>,
+        host_satisfied: &mut BTreeSet<String>,
+        next: &mut Vec<(String, bool)>,
+    ) -> Result<()> {
+        let deps: Vec<String> = info.depends.iter()
+            .chain(&info.makedepends)
+            .chain(&info.checkdepends)
+            .filter(|dep| !by_name.contains_key(aur::dep_name(dep)))
+            .cloned()
+            .collect();
+        let unsatisfied = pacman::unsatisfied(&deps)?;
+        for dep in deps {
+            let name = aur::dep_name(&dep);
+            if unsatisfied.contains(&dep) {
+                if pacman::in_repos(&dep) {
+                    repo_deps.insert(dep);
+                } else {
+                    next.push((name.to_string(), false));
+                }
+            } else if let Some(pkg) = foreign.iter()
+                .find(|pkg| pkg.name == name || pkg.provides.iter().any(|p| aur::dep_name(p) == name))
+                && !pacman::in_repos(&dep)
+            {
+                next.push((pkg.name.clone(), false));
+                host_satisfied.insert(dep);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves `targets` and their missing dependencies into a build plan.
+    /// Dependencies come from batched RPC info lookups; the full .SRCINFO is
+    /// only read from the clone when building. For `chroot` builds, installed
+    /// AUR packages that dependencies rely on are planned as well, but only
+    /// for the chroot.
+    pub async fn resolve(client: &Client, targets: &[String], chroot: bool) -> Result<Plan> {
+        let mut nodes: BTreeMap<String, Node> = BTreeMap::new();
+        let mut by_name: HashMap<String, String> = HashMap::new();
+        let mut repo_deps = BTreeSet::new();
+        let mut host_satisfied = BTreeSet::new();
+        let foreign: Vec<pacman::LocalPackage> = if chroot {
+            let names: BTreeSet<String> = pacman::get_installed_aur_packages()?.into_iter()
+                .map(|(name, _)| name)
+                .collect();
+            pacman::local_packages()?.into_iter().filter(|pkg| names.contains(&pkg.name)).collect()
+        } else {
+            Vec::new()
+        };
+        let mut missing = Vec::new();
+        let mut pending: Vec<(String, bool)> = targets.iter().map(|name| (name.clone(), true)).collect();
+
+        while !pending.is_empty() {
+            let lookup: Vec<String> = pending.iter()
+                .filter(|(name, _)| !by_name.contains_key(name))
+                .map(|(name, _)| name.clone())
+                .collect();
+            let found = aur::info(client, &lookup).await?;
+            let mut next = Vec::new();
+
+            for (name, is_target) in pending {
+                if let Some(pkgbase) = by_name.get(&name) {
+                    let node = nodes.get_mut(pkgbase).expect("indexed pkgbase");
+                    if let Some(pkg) = found.iter().find(|pkg| pkg.name == name) {
+                        node.packages.insert(name, pkg.version.clone());
+                    }
+                    node.as_deps &= !is_target;
+                    continue;
+                }
+
+                let Some(pkg) = found.iter().find(|pkg| pkg.name == name) else {
+                    if is_target {
+                        return Err(format!("Package {} was not found in the AUR", name).into());
+                    }
+                    missing.push(name);
+                    continue;
+                };
+
+                let pkgbase = pkg.package_base.clone().unwrap_or_else(|| name.clone());
+                let info = aur::Srcinfo::from_info(pkg);
+                for provided in &info.provides {
+                    by_name.entry(aur::dep_name(provided).to_string()).or_insert_with(|| pkgbase.clone());
+                }
+                by_name.insert(name.clone(), pkgbase.clone());
+                queue_deps(&info, &by_name, &foreign, &mut repo_deps, &mut host_satisfied, &mut next)?;
+
+                if let Some(node) = nodes.get_mut(&pkgbase) {
+                    // Another package of a pkgbase that is already planned.
+                    node.packages.insert(name, pkg.version.clone());
+                    node.as_deps &= !is_target;
+                    node.srcinfo.merge(info);
+                } else {
+                    let mut packages = BTreeMap::new();
+                    packages.insert(name, pkg.version.clone());
+                    nodes.insert(pkgbase, Node { srcinfo: info, packages, as_deps: !is_target });
+                }
+            }
+
+            pending = next;
+        }
+
+        if !missing.is_empty() {
+            missing.sort();
+            missing.dedup();
+            return Err(format!("Dependencies not found in the repositories or the AUR: {}", missing.join(", ")).into());
+        }
+
+        // A base is needed on the host if it was requested or something needed
+        // on the host depends on it at runtime and the host doesn't satisfy that
+        // dependency already.
+        let mut runtime: BTreeSet<String> = nodes.iter()
+            .filter(|(_, node)| !node.as_deps)
+            .map(|(pkgbase, _)| pkgbase.clone())
+            .collect();
+        loop {
+            let more: Vec<String> = runtime.iter()
+                .flat_map(|pkgbase| &nodes[pkgbase].srcinfo.depends)
+                .filter(|dep| !host_satisfied.contains(*dep))
+                .filter_map(|dep| by_name.get(aur::dep_name(dep)))
+                .filter(|pkgbase| !runtime.contains(*pkgbase))
+                .cloned()
+                .collect();
+            if more.is_empty() {
+                break;
+            }
+            runtime.extend(more);
+        }
+
+        let installed = pacman::installed_versions()?;
+        let mut bases: Vec<PlanBase> = nodes.iter()
+            .map(|(pkgbase, node)| {
+                let needs: BTreeSet<String> = node.srcinfo.depends.iter()
+                    .chain(&node.srcinfo.makedepends)
+                    .chain(&node.srcinfo.checkdepends)
+                    .filter_map(|dep| by_name.get(aur::dep_name(dep)))
+                    .filter(|other| *other != pkgbase)
+                    .cloned()
+                    .collect();
+                PlanBase {
+                    pkgbase: pkgbase.clone(),
+                    packages: node.packages.iter()
+                        .map(|(name, version)| PlanPackage {
+                            name: name.clone(),
+                            version: version.clone(),
+                            installed: installed.get(name).cloned(),
+                        })
+                        .collect(),
+                    as_deps: node.as_deps,
+                    make_only: !runtime.contains(pkgbase),
+                    needs: needs.into_iter().collect(),
+                }
+            })
+            .collect();
+
+        let mut layers = Vec::new();
+        let mut done = BTreeSet::new();
+        while !bases.is_empty() {
+            let (ready, blocked): (Vec<PlanBase>, Vec<PlanBase>) = bases.into_iter()
+                .partition(|base| base.needs.iter().all(|need| done.contains(need)));
+
+            if ready.is_empty() {
+                let names: Vec<&str> = blocked.iter().map(|base| base.pkgbase.as_str()).collect();
+                return Err(format!("Dependency cycle between: {}", names.join(", ")).into());
+            }
+
+            done.extend(ready.iter().map(|base| base.pkgbase.clone()));
+            layers.push(ready);
+            bases = blocked;
+        }
+
+        let conflicts = find_conflicts(&nodes, &pacman::local_packages()?);
+
+        Ok(Plan {
+            repo_deps: repo_deps.into_iter().collect(),
+            repo_targets: Vec::new(),
+            repo_upgrades: Vec::new(),
+            layers,
+            conflicts,
+            remove_conflicts: false,
+        })
+    }
+
+    /// Checks every plan package against the installed ones in both
+    /// directions, matching names as well as provides. Installed packages that
+    /// the plan itself upgrades are not conflicts.
+    fn find_conflicts(nodes: &BTreeMap<String, Node>, installed: &[pacman::LocalPackage]) -> Vec<Conflict> {
+        let in_plan: BTreeSet<&str> = nodes.values()
+            .flat_map(|node| node.packages.keys())
+            .map(|name| name.as_str())
+            .collect();
+        let mut conflicts: Vec<Conflict> = Vec::new();
+
+        for (pkgbase, node) in nodes {
+            let provided: Vec<&str> = node.srcinfo.provides.iter().map(|p| aur::dep_name(p)).collect();
+
+            for package in node.packages.keys() {
+                let names_of_target: Vec<&str> = std::iter::once(package.as_str()).chain(provided.iter().copied()).collect();
+
+                for local in installed.iter().filter(|local| !in_plan.contains(local.name.as_str())) {
+                    let names_of_local: Vec<&str> = std::iter::once(local.name.as_str())
+                        .chain(local.provides.iter().map(|p| aur::dep_name(p)))
+                        .collect();
+
+                    let conflict = if let Some(c) = node.srcinfo.conflicts.iter().find(|c| names_of_local.contains(&aur::dep_name(c))) {
+                        Some(format!("conflicts with {}", c))
+                    } else {
+                        local.conflicts.iter()
+                            .find(|c| names_of_target.contains(&aur::dep_name(c)))
+                            .map(|c| format!("{} conflicts with {}", local.name, c))
+                    };
+                    let replaces = node.srcinfo.replaces.iter().find(|r| aur::dep_name(r) == local.name);
+                    let replaced_only = conflict.is_none() && replaces.is_some();
+                    let reason = replaces.map(|r| format!("replaces {}", r)).or(conflict);
+
+                    if let Some(reason) = reason
+                        && !conflicts.iter().any(|c| c.package == *package && c.installed == local.name)
+                    {
+                        conflicts.push(Conflict {
+                            pkgbase: pkgbase.clone(),
+                            package: package.clone(),
+                            installed: local.name.clone(),
+                            reason,
+                            replaced_only,
+                        });
+                    }
+                }
+            }
+        }
+
+        conflicts
+    }
+}
+
+mod review {
+    use super::*;
+    use ring::digest;
+
+    const VCS_PREFIXES: [&str; 5] = ["git", "svn", "hg", "bzr", "fossil"];
+
+    enum Finding {
+        Verified { file: String, algorithm: String },
+        Mismatch { file: String, algorithm: String },
+        NotDownloaded { file: String },
+        SkippedNonVcs { source: String },
+        CountMismatch { array: String, sums: usize, sources: usize },
+        Unsupported { array: String },
+        DownloadFailed(String),
+    }
+
+    impl Finding {
+        fn is_problem(&self) -> bool {
+            !matches!(self, Finding::Verified { .. } | Finding::Unsupported { .. })
+        }
+    }
+
+    /// Downloads the sources of every pkgbase and checks them against the
+    /// .SRCINFO checksums independently of makepkg. Returns false if the user
+    /// chose not to build after problems were found.
+    pub async fn check_sources(dirs: &[(&str, &str)]) -> Result<bool> {
+        println!("\n==> Reviewing sources...");
+        let mut problems = 0;
+
+        for (pkgbase, dir) in dirs {
+            let findings = check_base(dir).await?;
+            println!("  {}:", pkgbase);
+            if findings.is_empty() {
+                println!("    no checksummed sources");
+            }
+
+            for finding in &findings {
+                match finding {
+                    Finding::Verified { file, algorithm } => println!("    ok          {} ({})", file, algorithm),
+                    Finding::Mismatch { file, algorithm } => println!("    MISMATCH    {} ({})", file, algorithm),
+                    Finding::NotDownloaded { file } => println!("    MISSING     {} was not downloaded", file),
+                    Finding::SkippedNonVcs { source } => println!("    SKIP        {} is not a VCS source but has no checksum", source),
+                    Finding::CountMismatch { array, sums, sources } => {
+                        println!("    COUNT       {} has {} entries for {} source(s)", array, sums, sources)
+                    }
+                    Finding::Unsupported { array } => println!("    note        {} not verified (algorithm not supported)", array),
+                    Finding::DownloadFailed(reason) => println!("    DOWNLOAD    {}", reason),
+                }
+            }
+            problems += findings.iter().filter(|f| f.is_problem()).count();
+        }
+
+        if problems == 0 {
+            return Ok(true);
+        }
+        confirm(&format!("{} source problem(s) found. Build anyway?", problems), false)
+    }
+
+    /// Looks for `validpgpkeys` missing from the user's keyring and offers to
+    /// import them from the configured keyserver or keyring file.
+    pub async fn check_keys(dirs: &[(&str, &str)]) -> Result<()> {
+        let mut needed: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for (pkgbase, dir) in dirs {
+            let srcinfo = aur::parse_srcinfo(&fs::read_to_string(Path::new(dir).join(".SRCINFO")).await?);
+            for key in srcinfo.validpgpkeys {
+                needed.entry(key).or_default().push(pkgbase);
+            }
+        }
+
+        let missing = missing_keys(&needed).await?;
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        println!("\n==> PGP keys missing from your keyring:");
+        for key in &missing {
+            println!("  {}  needed by {}", key, needed[key].join(", "));
+        }
+
+        let config = config::get();
+        println!("\nImport them from:");
+        println!("  1. keyserver {}", config.gpg_keyserver);
+        if let Some(keyring) = &config.gpg_keyring {
+            println!("  2. keyring file {}", keyring.display());
+        }
+        println!("Enter a number, or press Enter to skip:");
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+
+        let mut cmd = TokioCommand::new("gpg");
+        match (input.trim(), &config.gpg_keyring) {
+            ("1", _) => {
+                // dirmngr ignores the proxy environment variables.
+                if let Some(proxy) = &config.proxy {
+                    cmd.args(["--keyserver-options", &format!("http-proxy={}", proxy)]);
+                }
+                cmd.args(["--keyserver", &config.gpg_keyserver, "--recv-keys"]).args(&missing)
+            },
+            ("2", Some(keyring)) => cmd.arg("--import").arg(keyring),
+            _ => {
+                println!("Skipping key import; builds that check these signatures will fail.");
+                return Ok(());
+            }
+        };
+        if !cmd.status().await?.success() {
+            eprintln!("gpg reported an error while importing keys.");
+        }
+
+        let still_missing = missing_keys(&needed).await?;
+        if !still_missing.is_empty() {
+            println!("Still missing: {}", still_missing.join(", "));
+        }
+        Ok(())
+    }
+
+    async fn missing_keys(needed: &BTreeMap<String, Vec<&str>>) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for key in needed.keys() {
+            let found = TokioCommand::new("gpg")
+                .args(["--list-keys", key])
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null())
+                .status()
+                .await?
+                .success();
+            if !found {
+                missing.push(key.clone());
+            }
+        }
+        Ok(missing)
+    }
+
+    async fn check_base(dir: &str) -> Result<Vec<Finding>> {
+        let srcinfo = aur::parse_srcinfo(&fs::read_to_string(Path::new(dir).join(".SRCINFO")).await?);
+        let mut findings = Vec::new();
+
+        let output = TokioCommand::new("makepkg")
+            .args(["--verifysource", "--skipinteg"])
+            .envs(http::proxy_env())
+            .current_dir(dir)
+            .stdin(std::process::Stdio::null())
+            .output()
+            .await?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = stderr.lines().last().unwrap_or("makepkg --verifysource failed").trim().to_string();
+            findings.push(Finding::DownloadFailed(reason));
+        }
+
+        let arch = env::consts::ARCH;
+        for (suffix, sources) in srcinfo.sources.iter().filter(|(suffix, _)| suffix.is_empty() || *suffix == arch) {
+            for (array, sums) in &srcinfo.checksums {
+                let (algorithm, array_suffix) = array.split_once('_').unwrap_or((array, ""));
+                if array_suffix != suffix {
+                    continue;
+                }
+                if sums.len() != sources.len() {
+                    findings.push(Finding::CountMismatch { array: array.clone(), sums: sums.len(), sources: sources.len() });
+                }
+
+                let Some(algorithm_ref) = digest_algorithm(algorithm) else {
+                    findings.push(Finding::Unsupported { array: array.clone() });
+                    continue;
+                };
+
+                for (source, sum) in sources.iter().zip(sums) {
+                    if sum == "SKIP" {
+                        if !is_vcs(source) {
+                            findings.push(Finding::SkippedNonVcs { source: source.clone() });
+                        }
+                        continue;
+                    }
+
+                    let file = file_name(source);
+                    let Some(path) = locate(dir, &file) else {
+                        findings.push(Finding::NotDownloaded { file });
+                        continue;
+                    };
+                    let algorithm = algorithm.trim_end_matches("sums").to_string();
+                    if hash_file(&path, algorithm_ref)?.eq_ignore_ascii_case(sum) {
+                        findings.push(Finding::Verified { file, algorithm });
+                    } else {
+                        findings.push(Finding::Mismatch { file, algorithm });
+                    }
+                }
+            }
+        }
+
+        Ok(findings)
+    }
+
+    /// ring has no MD5 or BLAKE2, so md5sums and b2sums are left to makepkg.
+    fn digest_algorithm(array: &str) -> Option<&'static digest::Algorithm> {
+        match array {
+            "sha1sums" => Some(&digest::SHA1_FOR_LEGACY_USE_ONLY),
+            "sha256sums" => Some(&digest::SHA256),
+            "sha384sums" => Some(&digest::SHA384),
+            "sha512sums" => Some(&digest::SHA512),
+            _ => None,
+        }
+    }
+
+    fn hash_file(path: &Path, algorithm: &'static digest::Algorithm) -> Result<String> {
+        let mut file = std::fs::File::open(path)?;
+        let mut context = digest::Context::new(algorithm);
+        let mut buf = [0u8; 64 * 1024];
+        loop {
+            let n = io::Read::read(&mut file, &mut buf)?;
+            if n == 0 {
+                break;
+            }
+            context.update(&buf[..n]);
+        }
+        Ok(context.finish().as_ref().iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+
+    fn is_vcs(source: &str) -> bool {
+        let url = source.split_once("::").map_or(source, |(_, url)| url);
+        let scheme = url.split_once("://").map_or("", |(scheme, _)| scheme);
+        VCS_PREFIXES.iter().any(|vcs| scheme == *vcs || scheme.starts_with(&format!("{}+", vcs)))
+    }
+
+    /// The local file name makepkg uses for a source entry.
+    fn file_name(source: &str) -> String {
+        if let Some((name, _)) = source.split_once("::") {
+            return name.to_string();
+        }
+        let url = source.split(['#', '?']).next().unwrap_or(source);
+        url.trim_end_matches('/').rsplit('/').next().unwrap_or(url).to_string()
+    }
+
+    /// Downloaded sources live next to the PKGBUILD unless SRCDEST is set.
+    fn locate(dir: &str, file: &str) -> Option<PathBuf> {
+        let srcdest = env::var("SRCDEST").ok().or_else(|| {
+            let home = env::var("HOME").unwrap_or_default();
+            ["/etc/makepkg.conf".to_string(), format!("{}/.makepkg.conf", home)]
+                .iter()
+                .filter_map(|conf| std::fs::read_to_string(conf).ok())
+                .flat_map(|contents| {
+                    contents.lines()
+                        .filter_map(|line| line.trim().strip_prefix("SRCDEST="))
+                        .map(|value| value.trim_matches(['"', '\'']).to_string())
+                        .collect::<Vec<_>>()
+                })
+                .next_back()
+        });
+
+        std::iter::once(Path::new(dir).join(file))
+            .chain(srcdest.map(|srcdest| Path::new(&srcdest).join(file)))
+            .find(|path| path.is_file())
+    }
+}
+
+mod build {
+    use super::*;
+    use std::collections::{BTreeSet, HashMap};
+    use tokio::sync::OnceCell;
+
+    static CHROOT_READY: OnceCell<()> = OnceCell::const_new();
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct BuildOptions {
+        /// Build in a clean devtools chroot instead of on the host.
+        pub chroot: bool,
+        /// Don't ask for confirmation when installing the built packages.
+        pub noconfirm: bool,
+        /// How many pkgbases may build at the same time.
+        pub jobs: usize,
+    }
+
+    impl Default for BuildOptions {
+        fn default() -> Self {
+            BuildOptions { chroot: false, noconfirm: false, jobs: config::get().build_jobs }
+        }
+    }
+
+    enum Outcome {
+        Installed,
+        Failed(String),
+        Skipped(String),
+    }
+
+    /// Builds and installs everything in `plan`, layer by layer. Pkgbases within
+    /// a layer build concurrently; anything that depends on a failed pkgbase is
+    /// skipped. A summary is printed at the end.
+    pub async fn run_plan(plan: &plan::Plan, opts: BuildOptions) -> Result<()> {
+        // On the host makepkg needs every dependency installed up front; in a
+        // chroot, pacman -U pulls in the runtime ones when installing.
+        if !opts.chroot && !plan.repo_deps.is_empty() {
+            pacman::ensure_synced()?;
+            println!("\nInstalling repository dependencies...");
+            let mut cmd = TokioCommand::new("sudo");
+            cmd.args(["pacman", "-S", "--needed", "--asdeps"]);
+            if opts.noconfirm {
+                cmd.arg("--noconfirm");
+            }
+            if !cmd.args(&plan.repo_deps).status().await?.success() {
+                return Err("Failed to install repository dependencies".into());
+            }
+        }
+
+        if opts.chroot {
+            CHROOT_READY.get_or_try_init(prepare_chroot).await?;
+        }
+
+        let mut dirs = HashMap::new();
+        for base in plan.bases() {
+            dirs.insert(base.pkgbase.clone(), aur::clone_package_repo(&base.pkgbase).await?);
+        }
+
+        let review: Vec<(&str, &str)> = plan.bases()
+            .map(|base| (base.pkgbase.as_str(), dirs[&base.pkgbase].as_str()))
+            .collect();
+        review::check_keys(&review).await?;
+        if !review::check_sources(&review).await? {
+            return Err("Build cancelled after source review".into());
+        }
+
+        let mut outcomes: Vec<(String, Outcome)> = Vec::new();
+        let mut built: HashMap<String, Vec<PathBuf>> = HashMap::new();
+
+        for layer in &plan.layers {
+            let mut runnable = Vec::new();
+            for base in layer {
+                let failed_need = base.needs.iter().find(|need| !built.contains_key(*need));
+                match failed_need {
+                    Some(need) => outcomes.push((base.pkgbase.clone(), Outcome::Skipped(need.clone()))),
+                    None => runnable.push(base),
+                }
+            }
+
+            let results = stream::iter(runnable)
+                .map(|base| {
+                    let dir = dirs[&base.pkgbase].clone();
+                    let deps = dependency_files(plan, base, &built);
+                    async move {
+                        println!("==> Building {}...", base.pkgbase);
+                        let result = build(base, &dir, opts, &deps).await;
+                        (base, result)
+                    }
+                })
+                .buffer_unordered(opts.jobs.max(1))
+                .collect::<Vec<_>>()
+                .await;
+
+            let mut layer_built = Vec::new();
+            for (base, result) in results {
+                match result {
+                    Ok(files) => layer_built.push((base, files)),
+                    Err(e) => outcomes.push((base.pkgbase.clone(), Outcome::Failed(e.to_string()))),
+                }
+            }
+
+            let (explicit, deps): (Vec<_>, Vec<_>) = layer_built.iter()
+                .filter(|(base, _)| !(opts.chroot && base.make_only))
+                .partition(|(base, _)| !base.as_deps);
+
+            let mut install_failed = Vec::new();
+            for (group, as_deps) in [(explicit, false), (deps, true)] {
+                if group.is_empty() {
+                    continue;
+                }
+                let files: Vec<&PathBuf> = group.iter().flat_map(|(_, files)| files).collect();
+                let group_conflicts: Vec<&plan::Conflict> = plan.conflicts.iter()
+                    .filter(|conflict| plan.remove_conflicts && group.iter().any(|(base, _)| base.pkgbase == conflict.pkgbase))
+                    .collect();
+                let result = install(&files, as_deps, !group_conflicts.is_empty(), opts.noconfirm).await;
+                if result.is_ok() {
+                    let replaced: Vec<&str> = group_conflicts.iter()
+                        .filter(|conflict| conflict.replaced_only)
+                        .map(|conflict| conflict.installed.as_str())
+                        .collect();
+                    if let Err(e) = remove_replaced(&replaced).await {
+                        eprintln!("Warning: {}", e);
+                    }
+                }
+                if let Err(e) = result {
+                    for (base, _) in group {
+                        outcomes.push((base.pkgbase.clone(), Outcome::Failed(e.to_string())));
+                        install_failed.push(base.pkgbase.clone());
+                    }
+                }
+            }
+
+            for (base, files) in layer_built {
+                if install_failed.contains(&base.pkgbase) {
+                    continue;
+                }
+                if let Err(e) = history::archive(&files).await {
+                    eprintln!("Warning: could not archive packages of {}: {}", base.pkgbase, e);
+                }
+                outcomes.push((base.pkgbase.clone(), Outcome::Installed));
+                built.insert(base.pkgbase.clone(), files);
+            }
+        }
+
+        println!("\nBuild summary:");
+        let mut failures = 0;
+        for (pkgbase, outcome) in &outcomes {
+            match outcome {
+                Outcome::Installed => println!("  ok       {}", pkgbase),
+                Outcome::Failed(reason) => {
+                    failures += 1;
+                    println!("  failed   {} ({})", pkgbase, reason);
+                }
+                Outcome::Skipped(need) => {
+                    failures += 1;
+                    println!("  skipped  {} (needs {})", pkgbase, need);
+                }
+            }
+        }
+
+        if failures > 0 {
+            return Err(format!("{} of {} package(s) were not installed", failures, outcomes.len()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Package files of every pkgbase `base` transitively needs, for `-I`.
+    fn dependency_files(plan: &plan::Plan, base: &plan::PlanBase, built: &HashMap<String, Vec<PathBuf>>) -> Vec<PathBuf> {
+        let mut seen = BTreeSet::new();
+        let mut stack: Vec<&String> = base.needs.iter().collect();
+
+        while let Some(pkgbase) = stack.pop() {
+            if seen.insert(pkgbase.clone())
+                && let Some(dep) = plan.bases().find(|other| &other.pkgbase == pkgbase)
+            {
+                stack.extend(&dep.needs);
+            }
+        }
+
+        seen.iter()
+            .filter_map(|pkgbase| built.get(pkgbase))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Builds one pkgbase and returns the package files of the plan's packages.
+    /// Output is always logged; it is only shown live when builds run one at a time.
+    async fn build(base: &plan::PlanBase, package_dir: &str, opts: BuildOptions, deps: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut cmd = if opts.chroot {
+            let mut cmd = TokioCommand::new("makechrootpkg");
+            cmd.arg("-c").arg("-r").arg(paths::chroot_dir());
+            if opts.jobs > 1 {
+                cmd.args(["-l", &base.pkgbase]);
+            }
+            for dep in deps {
+                cmd.arg("-I").arg(dep);
+            }
+            cmd
+        } else {
+            TokioCommand::new("makepkg")
+        };
+        cmd.current_dir(package_dir).envs(http::proxy_env());
+
+        let (status, log) = logs::run(cmd, &base.pkgbase, opts.jobs <= 1).await?;
+        if !status.success() {
+            return Err(format!("build failed, see {}", log.display()).into());
+        }
+
+        let files: Vec<PathBuf> = package_list(package_dir).await?
+            .into_iter()
+            .filter(|file| {
+                package_file_info(file)
+                    .is_some_and(|(name, _)| base.packages.iter().any(|wanted| wanted.name == name))
+            })
+            .collect();
+
+        if files.is_empty() {
+            return Err("no package files were built".into());
+        }
+
+        Ok(files)
+    }
+
+    /// Removes packages that were replaced without conflicting, which `pacman -U`
+    /// leaves installed. Dependency checks still apply.
+    async fn remove_replaced(packages: &[&str]) -> Result<()> {
+        let installed = pacman::installed_versions()?;
+        let packages: Vec<&str> = packages.iter().copied().filter(|name| installed.contains_key(*name)).collect();
+        if packages.is_empty() {
+            return Ok(());
+        }
+        let status = TokioCommand::new("sudo")
+            .args(["pacman", "-R", "--noconfirm"])
+            .args(&packages)
+            .status()
+            .await?;
+        if !status.success() {
+            return Err(format!("could not remove replaced package(s) {}", packages.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    /// With `remove_conflicts`, pacman's conflict question is answered with yes
+    /// (`--ask=4`), so the conflicting packages are removed in the same
+    /// transaction, with dependency checks.
+    async fn install(files: &[&PathBuf], as_deps: bool, remove_conflicts: bool, noconfirm: bool) -> Result<()> {
+        let mut cmd = TokioCommand::new("sudo");
+        cmd.args(["pacman", "-U"]);
+        if as_deps {
+            cmd.arg("--asdeps");
+        }
+        if remove_conflicts {
+            cmd.arg("--ask=4");
+        }
+        if noconfirm {
+            cmd.arg("--noconfirm");
+        }
+
+        if !cmd.args(files).status().await?.success() {
+            return Err("pacman -U failed".into());
+        }
+        Ok(())
+    }
+
+    /// Package name and version from a file like `foo-bar-1:1.0-1-x86_64.pkg.tar.zst`.
+    pub fn package_file_info(path: &Path) -> Option<(String, String)> {
+        let file = path.file_name()?.to_str()?;
+        let stem = &file[..file.find(".pkg.tar")?];
+        let mut parts = stem.rsplitn(4, '-');
+        let (_arch, pkgrel, pkgver, name) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+        Some((name.to_string(), format!("{}-{}", pkgver, pkgrel)))
+    }
+
+    /// Package files makepkg reports for `package_dir` that actually exist on disk.
+    async fn package_list(package_dir: &str) -> Result<Vec<PathBuf>> {
+        let output = TokioCommand::new("makepkg")
+            .arg("--packagelist")
+            .current_dir(package_dir)
+            .output()
+            .await?;
+
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect())
+    }
+
+    /// Creates the persistent chroot on first use and upgrades it otherwise.
+    async fn prepare_chroot() -> Result<()> {
+        if !Path::new("/usr/bin/makechrootpkg").exists() {
+            return Err("Chroot builds need devtools. Install it with 'sudo pacman -S devtools'.".into());
+        }
+
+        let root = paths::chroot_dir().join("root");
+        let status = if root.exists() {
+            println!("Updating build chroot {}...", root.display());
+            TokioCommand::new("sudo")
+                .arg("arch-nspawn")
+                .arg(&root)
+                .args(["pacman", "-Syu", "--noconfirm"])
+                .status()
+                .await?
+        } else {
+            fs::create_dir_all(paths::chroot_dir()).await?;
+            println!("Creating build chroot {}...", root.display());
+            TokioCommand::new("sudo")
+                .arg("mkarchroot")
+                .arg(&root)
+                .arg("base-devel")
+                .status()
+                .await?
+        };
+
+        if !status.success() {
+            return Err(format!("Failed to prepare build chroot {}", root.display()).into());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn info(file: &str) -> Option<(String, String)> {
+            package_file_info(Path::new(file))
+        }
+
+        #[test]
+        fn package_file_info_splits_name_and_version() {
+            assert_eq!(info("/tmp/yay-12.3.5-1-x86_64.pkg.tar.zst"), Some(("yay".into(), "12.3.5-1".into())));
+            assert_eq!(info("foo-bar-git-1:0.r12.abc-2-any.pkg.tar.xz"), Some(("foo-bar-git".into(), "1:0.r12.abc-2".into())));
+            assert_eq!(info("python-foo-1.0-1.1-x86_64.pkg.tar.zst"), Some(("python-foo".into(), "1.0-1.1".into())));
+        }
+
+        #[test]
+        fn package_file_info_rejects_other_files() {
+            assert_eq!(info("PKGBUILD"), None);
+            assert_eq!(info("yay-12.3.5.tar.gz"), None);
+            assert_eq!(info("x86_64.pkg.tar.zst"), None);
+        }
+    }
+}
+
+mod pins {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    /// A package held at the version (and AUR commit) it had when pinned.
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    pub struct Pin {
+        pub version: String,
+        pub commit: Option<String>,
+        pub time: u64,
+    }
+
+    pub fn load() -> Result<BTreeMap<String, Pin>> {
+        match std::fs::read_to_string(paths::pins_file()) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(|e| format!("Corrupt pins file: {}", e).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save(pins: &BTreeMap<String, Pin>) -> Result<()> {
+        std::fs::create_dir_all(paths::state_dir())?;
+        let contents = serde_json::to_string_pretty(pins).map_err(|e| e.to_string())?;
+        std::fs::write(paths::pins_file(), contents)?;
+        Ok(())
+    }
+}
+
+mod history {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    const PACMAN_CACHE: &str = "/var/cache/pacman/pkg";
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Change {
+        pub name: String,
+        /// Version before the transaction, `None` if it was not installed.
+        pub old: Option<String>,
+        /// Version after the transaction, `None` if it was removed.
+        pub new: Option<String>,
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct Transaction {
+        pub id: u64,
+        pub time: u64,
+        pub action: String,
+        pub changes: Vec<Change>,
+    }
+
+    pub fn load() -> Result<Vec<Transaction>> {
+        let contents = match std::fs::read_to_string(paths::history_file()) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        contents.lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| serde_json::from_str(line).map_err(|e| format!("Corrupt history entry: {}", e).into()))
+            .collect()
+    }
+
+    /// Compares the installed packages against `before` and appends whatever
+    /// changed as a new transaction. Nothing is written if nothing changed.
+    fn record(action: &str, before: &BTreeMap<String, String>) -> Result<Option<u64>> {
+        let after = pacman::installed_versions()?;
+        let mut changes: Vec<Change> = after.iter()
+            .filter(|(name, version)| before.get(*name) != Some(version))
+            .map(|(name, version)| Change {
+                name: name.clone(),
+                old: before.get(name).cloned(),
+                new: Some(version.clone()),
+            })
+            .collect();
+        changes.extend(before.iter()
+            .filter(|(name, _)| !after.contains_key(*name))
+            .map(|(name, version)| Change { name: name.clone(), old: Some(version.clone()), new: None }));
+
+        if changes.is_empty() {
+            return Ok(None);
+        }
+
+        let id = load()?.last().map_or(1, |last| last.id + 1);
+        let transaction = Transaction { id, time: clock::unix_now(), action: action.to_string(), changes };
+        let line = serde_json::to_string(&transaction).map_err(|e| e.to_string())? + "\n";
+
+        std::fs::create_dir_all(paths::state_dir())?;
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(paths::history_file())?
+            .write_all(line.as_bytes())?;
+
+        Ok(Some(id))
+    }
+
+    /// Records like `record`, but only warns if that fails, so the operation's
+    /// own result is what gets reported.
+    pub fn record_or_warn(action: &str, before: &BTreeMap<String, String>) -> Option<u64> {
+        record(action, before).unwrap_or_else(|e| {
+            eprintln!("Warning: could not record the {} in the history: {}", action, e);
+            None
+        })
+    }
+
+    /// Keeps copies of installed package files so they can be reinstalled later.
+    pub async fn archive(files: &[PathBuf]) -> Result<()> {
+        let dir = paths::archive_dir();
+        fs::create_dir_all(&dir).await?;
+        for file in files {
+            if let Some(name) = file.file_name() {
+                fs::copy(file, dir.join(name)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Every package file for `name` in the archive or pacman's cache, with
+    /// its version.
+    pub fn package_files(name: &str) -> Vec<(String, PathBuf)> {
+        [paths::archive_dir(), PathBuf::from(PACMAN_CACHE)].iter()
+            .filter_map(|dir| std::fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| !path.to_string_lossy().ends_with(".sig"))
+            .filter_map(|path| match build::package_file_info(&path) {
+                Some((file_name, version)) if file_name == name => Some((version, path)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+mod cache {
+    use super::*;
+
+    /// A pkgbase clone in the cache directory.
+    #[derive(Debug)]
+    pub struct Entry {
+        pub pkgbase: String,
+        pub path: PathBuf,
+        pub size: u64,
+        /// Newest modification time of anything directly inside the clone.
+        pub last_used: Option<u64>,
+    }
+
+    fn mtime(metadata: &std::fs::Metadata) -> Option<u64> {
+        metadata.modified().ok()?.duration_since(std::time::UNIX_EPOCH).ok().map(|d| d.as_secs())
+    }
+
+    /// Total size of `path` without following symlinks.
+    pub fn size(path: &Path) -> u64 {
+        let Ok(metadata) = std::fs::symlink_metadata(path) else {
+            return 0;
+        };
+        if !metadata.is_dir() {
+            return metadata.len();
+        }
+        std::fs::read_dir(path)
+            .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| size(&entry.path())).sum())
+            .unwrap_or(0)
+    }
+
+    /// Every cached clone, sorted by pkgbase. The chroot, the archive, and
+    /// cached AUR responses are not clones and are left out.
+    pub fn clones() -> Result<Vec<Entry>> {
+        let dir = PathBuf::from(paths::cache_dir());
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut clones: Vec<Entry> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| {
+                path.is_dir() && ![paths::chroot_dir(), paths::archive_dir(), paths::response_cache_dir()].contains(path)
+            })
+            .map(|path| Entry {
+                pkgbase: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+                size: size(&path),
+                last_used: std::fs::read_dir(&path).ok()
+                    .and_then(|entries| entries
+                        .filter_map(|entry| entry.ok()?.metadata().ok())
+                        .filter_map(|metadata| mtime(&metadata))
+                        .max()),
+                path,
+            })
+            .collect();
+        clones.sort_by(|a, b| a.pkgbase.cmp(&b.pkgbase));
+        Ok(clones)
+    }
+
+    /// Removes makepkg's `src/` and `pkg/` from every clone, and cached AUR
+    /// responses, returning the bytes freed.
+    pub fn clean_build_dirs() -> Result<u64> {
+        let responses = paths::response_cache_dir();
+        let mut freed = size(&responses);
+        if responses.is_dir() {
+            std::fs::remove_dir_all(&responses)?;
+        }
+        for clone in clones()? {
+            for dir in ["src", "pkg"].map(|name| clone.path.join(name)) {
+                if dir.is_dir() {
+                    freed += size(&dir);
+                    std::fs::remove_dir_all(&dir)?;
+                }
+            }
+        }
+        Ok(freed)
+    }
+
+    /// Clones none of whose packages (per their .SRCINFO) are installed.
+    pub fn unused_clones() -> Result<Vec<Entry>> {
+        let installed = pacman::installed_versions()?;
+        Ok(clones()?
+            .into_iter()
+            .filter(|clone| {
+                let names = std::fs::read_to_string(clone.path.join(".SRCINFO"))
+                    .map(|srcinfo| aur::parse_srcinfo(&srcinfo).pkgnames)
+                    .unwrap_or_else(|_| vec![clone.pkgbase.clone()]);
+                !names.iter().any(|name| installed.contains_key(name))
+            })
+            .collect())
+    }
+
+    /// Built package files in the clones and the archive beyond the `keep`
+    /// newest of each package.
+    pub fn old_package_files(keep: usize) -> Result<Vec<PathBuf>> {
+        let mut dirs: Vec<PathBuf> = clones()?.into_iter().map(|clone| clone.path).collect();
+        dirs.push(paths::archive_dir());
+
+        let mut by_name: BTreeMap<String, Vec<(u64, PathBuf)>> = BTreeMap::new();
+        for path in dirs.iter()
+            .filter_map(|dir| std::fs::read_dir(dir).ok())
+            .flatten()
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| !path.to_string_lossy().ends_with(".sig"))
+        {
+            if let Some((name, _)) = build::package_file_info(&path) {
+                let time = std::fs::metadata(&path).ok().and_then(|metadata| mtime(&metadata)).unwrap_or(0);
+                by_name.entry(name).or_default().push((time, path));
+            }
+        }
+
+        Ok(by_name.into_values()
+            .flat_map(|mut files| {
+                files.sort_by_key(|(time, _)| std::cmp::Reverse(*time));
+                files.into_iter().skip(keep).map(|(_, path)| path)
+            })
+            .collect())
+    }
+}
+
+mod rebuild {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// An installed foreign package that no longer fits the system.
+    #[derive(Debug)]
+    pub struct Broken {
+        pub package: String,
+        pub reasons: BTreeSet<String>,
+    }
+
+    /// "major.minor" of an installed package, e.g. "3.12" for python 3.12.7-1.
+    fn minor_version(installed: &BTreeMap<String, String>, name: &str) -> Option<String> {
+        let version = installed.get(name)?;
+        let version = version.split_once(':').map_or(version.as_str(), |(_, v)| v);
+        let mut parts = version.split('.');
+        Some(format!("{}.{}", parts.next()?, parts.next()?))
+    }
+
+    /// The directory component after `prefix`, e.g. "3.11" for
+    /// /usr/lib/python3.11/site-packages/... with prefix /usr/lib/python.
+    fn versioned_dir<'a>(path: &'a str, prefix: &str) -> Option<&'a str> {
+        let rest = path.strip_prefix(prefix)?;
+        let dir = rest.split('/').next()?;
+        dir.starts_with(|c: char| c.is_ascii_digit()).then_some(dir)
+    }
+
+    fn is_elf(path: &str) -> bool {
+        let mut magic = [0u8; 4];
+        std::fs::File::open(path)
+            .and_then(|mut file| io::Read::read_exact(&mut file, &mut magic))
+            .is_ok_and(|_| magic == *b"\x7fELF")
+    }
+
+    /// Shared libraries `path` links against that cannot be found.
+    fn missing_libraries(path: &str) -> Vec<String> {
+        let Ok(output) = Command::new("ldd").arg(path).env("LC_ALL", "C").output() else {
+            return Vec::new();
+        };
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| line.contains("=> not found"))
+            .filter_map(|line| line.split_whitespace().next().map(|lib| lib.to_string()))
+            .collect()
+    }
+
+    /// Inspects the files of every installed foreign package for shared
+    /// libraries that are gone and for python or perl modules installed for
+    /// another interpreter version than the one on the system.
+    pub fn check() -> Result<Vec<Broken>> {
+        let foreign = pacman::get_installed_aur_packages()?;
+        if foreign.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let installed = pacman::installed_versions()?;
+        let python = minor_version(&installed, "python");
+        let perl = minor_version(&installed, "perl");
+
+        let output = Command::new("pacman")
+            .arg("-Ql")
+            .args(foreign.iter().map(|(name, _)| name))
+            .output()?;
+        let mut files: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        let listing = String::from_utf8_lossy(&output.stdout);
+        for line in listing.lines() {
+            if let Some((package, path)) = line.split_once(' ')
+                && !path.ends_with('/')
+            {
+                files.entry(package).or_default().push(path);
+            }
+        }
+
+        let mut broken = Vec::new();
+        for (package, paths) in files {
+            let mut reasons = BTreeSet::new();
+            for path in paths {
+                // python2 modules live next to python3 ones and aren't affected
+                // by python3 upgrades.
+                if let (Some(dir), Some(current)) = (versioned_dir(path, "/usr/lib/python"), &python)
+                    && dir != current
+                    && dir.split('.').next() == current.split('.').next()
+                {
+                    reasons.insert(format!("has modules for python {} (installed: {})", dir, current));
+                }
+                if let (Some(dir), Some(current)) = (versioned_dir(path, "/usr/lib/perl5/"), &perl)
+                    && dir != current
+                {
+                    reasons.insert(format!("has modules for perl {} (installed: {})", dir, current));
+                }
+                if is_elf(path) {
+                    for lib in missing_libraries(path) {
+                        reasons.insert(format!("{} is missing {}", path, lib));
+                    }
+                }
+            }
+            if !reasons.is_empty() {
+                broken.push(Broken { package: package.to_string(), reasons });
+            }
+        }
+        Ok(broken)
+    }
+}
+
+mod selection {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Parses a menu selection against `items`, given as (number, source)
+    /// where source is "aur" or a repository name. Accepts numbers, ranges
+    /// (`1-5`), exclusions (`^3`, `^2-4`), and source filters (`aur`, `repo`,
+    /// `extra`, `^aur`). A selection made only of exclusions starts from
+    /// everything; blank input selects nothing. Returns the selected numbers
+    /// in ascending order.
+    pub fn parse(input: &str, items: &[(usize, &str)]) -> std::result::Result<Vec<usize>, String> {
+        let mut included = BTreeSet::new();
+        let mut excluded = BTreeSet::new();
+        let (mut any_included, mut any_excluded) = (false, false);
+
+        for token in input.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty()) {
+            let (exclude, token) = match token.strip_prefix('^') {
+                Some(rest) => (true, rest),
+                None => (false, token),
+            };
+            let numbers = matching(token, items)?;
+            if exclude {
+                any_excluded = true;
+                excluded.extend(numbers);
+            } else {
+                any_included = true;
+                included.extend(numbers);
+            }
+        }
+
+        if any_excluded && !any_included {
+            included.extend(items.iter().map(|(number, _)| *number));
+        }
+
+        Ok(included.difference(&excluded).copied().collect())
+    }
+
+    fn matching(token: &str, items: &[(usize, &str)]) -> std::result::Result<Vec<usize>, String> {
+        let in_range = |n: usize| items.iter().any(|(number, _)| *number == n);
+
+        if token.chars().all(|c| c.is_ascii_digit() || c == '-') {
+            let (start, end) = match token.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => (token, token),
+            };
+            let (Ok(start), Ok(end)) = (start.parse::<usize>(), end.parse::<usize>()) else {
+                return Err(format!("'{}' is not a number or range", token));
+            };
+            if start > end {
+                return Err(format!("Range '{}' goes backwards", token));
+            }
+            if let Some(n) = (start..=end).find(|n| !in_range(*n)) {
+                return Err(format!("There is no package number {}", n));
+            }
+            return Ok((start..=end).collect());
+        }
+
+        let filter = token.to_lowercase();
+        let matches = |source: &str| source == filter || (filter == "repo" && source != "aur");
+        let known = filter == "aur" || filter == "repo" || items.iter().any(|(_, source)| *source == filter);
+        if !known {
+            return Err(format!("'{}' is not a number, range, or repository", token));
+        }
+
+        Ok(items.iter()
+            .filter(|(_, source)| matches(source))
+            .map(|(number, _)| *number)
+            .collect())
+    }
+
+    /// Reads selections until one parses. Returns `None` on 'back' or at the
+    /// end of input. An empty line selects everything when `empty_selects_all`
+    /// is set.
+    pub fn prompt(items: &[(usize, &str)], empty_selects_all: bool) -> Result<Option<Vec<usize>>> {
+        loop {
+            let mut input = String::new();
+            if io::stdin().read_line(&mut input)? == 0 {
+                return Ok(None);
+            }
+            let input = input.trim();
+
+            if input.eq_ignore_ascii_case("back") {
+                return Ok(None);
+            }
+            if input.is_empty() && empty_selects_all {
+                return Ok(Some(items.iter().map(|(number, _)| *number).collect()));
+            }
+
+            match parse(input, items) {
+                Ok(selected) if !selected.is_empty() => return Ok(Some(selected)),
+                Ok(_) => println!("Nothing selected. Try again, or type 'back' to cancel:"),
+                Err(e) => println!("{}. Try again, or type 'back' to cancel:", e),
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const ITEMS: &[(usize, &str)] = &[(1, "aur"), (2, "aur"), (3, "extra"), (4, "core"), (5, "extra")];
+
+        #[test]
+        fn blank_input_selects_nothing() {
+            assert_eq!(parse("", ITEMS), Ok(vec![]));
+            assert_eq!(parse("   ", ITEMS), Ok(vec![]));
+            assert_eq!(parse(" , ", ITEMS), Ok(vec![]));
+        }
+
+        #[test]
+        fn exclusions_alone_start_from_everything() {
+            assert_eq!(parse("^3", ITEMS), Ok(vec![1, 2, 4, 5]));
+            assert_eq!(parse("^2-4", ITEMS), Ok(vec![1, 5]));
+            assert_eq!(parse("^aur", ITEMS), Ok(vec![3, 4, 5]));
+        }
+
+        #[test]
+        fn ranges_with_exclusions() {
+            assert_eq!(parse("1-5 ^2", ITEMS), Ok(vec![1, 3, 4, 5]));
+            assert_eq!(parse("1,3 5", ITEMS), Ok(vec![1, 3, 5]));
+            assert_eq!(parse("2-2", ITEMS), Ok(vec![2]));
+        }
+
+        #[test]
+        fn rejects_bad_ranges_and_tokens() {
+            assert!(parse("4-2", ITEMS).is_err());
+            assert!(parse("6", ITEMS).is_err());
+            assert!(parse("0", ITEMS).is_err());
+            assert!(parse("1-", ITEMS).is_err());
+            assert!(parse("foo", ITEMS).is_err());
+            assert!(parse("1 multilib", ITEMS).is_err());
+        }
+
+        #[test]
+        fn source_filters() {
+            assert_eq!(parse("repo", ITEMS), Ok(vec![3, 4, 5]));
+            assert_eq!(parse("extra", ITEMS), Ok(vec![3, 5]));
+            assert_eq!(parse("AUR", ITEMS), Ok(vec![1, 2]));
+            assert_eq!(parse("repo ^extra", ITEMS), Ok(vec![4]));
+        }
+    }
+}
+
+mod display {
+    use super::*;
+
+    pub fn print_package(index: usize, pkg: &AurPackage) {
+        let installed = if pacman::is_installed(&pkg.name) { " (Installed)" } else { "" };
+        println!("{}. {} ({}){}", index, pkg.name, pkg.version, installed);
+        if let Some(desc) = &pkg.description {
+            println!("   description: {}", desc);
+        }
+        println!("   Votes: {}", pkg.num_votes.unwrap_or(0));
+        println!("-------------------------");
+    }
+
+    pub fn print_official_pkg(index: usize, line: &str, description: Option<&str>) {
+        if let Some(repo_start) = line.find('[') {
+            let parts: Vec<&str> = line[..repo_start].split_whitespace().collect();
+            if !parts.is_empty() {
+                let name = parts[0];
+                let version = parts.get(1).unwrap_or(&"");
+                let pkg_name = name.split('/').next_back().unwrap_or(name);
+                let installed = if pacman::is_installed(pkg_name) { " (Installed)" } else { "" };
+                println!("{}. {} ({}){}", index, name, version, installed);
+                if let Some(desc) = description {
+                    println!("   description: {}", desc);
+                }
+            }
+        }
+        println!("-------------------------");
+    }
+
+    /// A byte count in pacman's units, e.g. "1.50 MiB".
+    pub fn format_size(bytes: u64) -> String {
+        let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+        let mut size = bytes as f64;
+        let mut unit = 0;
+        while size >= 1024.0 && unit < units.len() - 1 {
+            size /= 1024.0;
+            unit += 1;
+        }
+        format!("{:.2} {}", size, units[unit])
+    }
+
+    pub fn version_change(old: Option<&str>, new: &str) -> String {
+        match old {
+            Some(old) => format!("{} → {}", old, new),
+            None => new.to_string(),
+        }
+    }
+
+    pub fn print_plan(plan: &plan::Plan) {
+        if !plan.repo_targets.is_empty() {
+            println!("\nRepository packages:");
+            for pkg in &plan.repo_targets {
+                println!("  {} ({})", pkg.name, version_change(pkg.installed.as_deref(), &pkg.version));
+            }
+        }
+
+        if !plan.repo_upgrades.is_empty() {
+            println!("\nRepository upgrades ({}):", plan.repo_upgrades.len());
+            for pkg in &plan.repo_upgrades {
+                println!("  {} ({})", pkg.name, version_change(pkg.installed.as_deref(), &pkg.version));
+            }
+        }
+
+        if !plan.repo_deps.is_empty() {
+            println!("\nRepository dependencies ({}):", plan.repo_deps.len());
+            println!("  {}", plan.repo_deps.join(" "));
+        }
+
+        if plan.layers.is_empty() && !plan.repo_targets.is_empty() {
+            return;
+        }
+        println!("\nAUR packages in build order:");
+        if plan.layers.is_empty() {
+            println!("  none");
+        }
+        for (i, layer) in plan.layers.iter().enumerate() {
+            for base in layer {
+                let packages: Vec<String> = base.packages.iter()
+                    .map(|pkg| format!("{} ({})", pkg.name, version_change(pkg.installed.as_deref(), &pkg.version)))
+                    .collect();
+                let reason = if base.as_deps { " [dependency]" } else { "" };
+                println!("  {}. {}{}", i + 1, packages.join(", "), reason);
+            }
+        }
+
+        if !plan.conflicts.is_empty() {
+            println!("\nConflicts with installed packages:");
+            for conflict in &plan.conflicts {
+                println!("  {} must replace {} ({})", conflict.package, conflict.installed, conflict.reason);
+            }
+        }
+    }
+
+    pub fn print_info(entry: &output::Entry) {
+        let (name, version, description, url) = match &entry.package {
+            output::Package::Aur(pkg) => (&pkg.name, &pkg.version, &pkg.description, &pkg.url),
+            output::Package::Repo(pkg) => (&pkg.name, &pkg.version, &pkg.description, &pkg.url),
+        };
+        let none = || "None".to_string();
+        println!("Name          : {}", name);
+        match &entry.package {
+            output::Package::Aur(_) => println!("Source        : AUR"),
+            output::Package::Repo(pkg) => println!("Source        : {}", pkg.repo),
+        }
+        println!("Version       : {}", version);
+        println!("Description   : {}", description.clone().unwrap_or_else(none));
+        println!("URL           : {}", url.clone().unwrap_or_else(none));
+        if let output::Package::Aur(pkg) = &entry.package {
+            println!("Package Base  : {}", pkg.package_base.as_deref().unwrap_or(name));
+            println!("Votes         : {}", pkg.num_votes.unwrap_or(0));
+            println!("Maintainer    : {}", pkg.maintainer.clone().unwrap_or_else(|| "None (orphaned)".to_string()));
+            println!("Out of Date   : {}", pkg.out_of_date.map_or_else(|| "No".to_string(), clock::format));
+        }
+        println!("Installed     : {}", entry.installed.clone().unwrap_or_else(|| "No".to_string()));
+    }
+
+    pub fn print_help() {
+        println!("Available commands:");
+        println!("  search, s <package>         Search for a package in the AUR and official repositories.");
+        println!("  info <package>...           Show details of packages from the repositories or the AUR.");
+        println!("  install, i <package>...     Install packages by name (aur/<name> or <repo>/<name> to pick).");
+        println!("  uninstall, ui <package>...  Uninstall packages, checking what depends on them first.");
+        println!("  update, up [--repo|--aur]   Upgrade official packages, then update AUR packages.");
+        println!("  outdated [--repo|--aur]     List available updates; exits 100 if there are any.");
+        println!("  metrics [--textfile <path>] Write package state metrics in the Prometheus text format.");
+        println!("  log [--list] <package> [n]  Show the latest (or n-th) build log of a package, or list past builds.");
+        println!("  history                     List the transactions aurorus performed.");
+        println!("  rollback <id>               Undo a transaction by reinstalling the previous versions.");
+        println!("  downgrade <package>         Reinstall the previously built version of a package.");
+        println!("  clean, autoremove           Remove dependencies that nothing requires anymore.");
+        println!("  cache [list|clean|prune|keep <n>]");
+        println!("                              Show the cache, remove build dirs, prune clones of removed");
+        println!("                              packages, or keep only the last n built files per package.");
+        println!("  checkrebuild                Find AUR packages broken by library upgrades and rebuild them.");
+        println!("  pin [<package> [version]]   Hold a package so updates skip it, or list pinned packages.");
+        println!("  unpin <package>             Offer updates for a pinned package again.");
+        println!("  help                        Show this help message.");
+        println!("  exit                        Exit the application.");
+        println!();
+        println!("install, update, and checkrebuild accept --chroot to build AUR packages in a clean chroot (needs devtools).");
+        println!("install and update accept --dry-run to print the full plan without cloning, building, or calling sudo;");
+        println!("uninstall and clean accept it to only show what would be removed.");
+        println!("Any command accepts --offline to answer AUR queries from the metadata dump,");
+        println!("and --refresh to ignore AUR responses cached in the last few minutes.");
+        println!("search, info, outdated, and --dry-run plans accept --json (or --ndjson, one value per line).");
+        println!("Any command can also be run directly, e.g. 'aurorus update --dry-run'.");
+    }
+
+}
+
+mod output {
+    use super::*;
+    use serde::Serialize;
+    use std::sync::atomic::{AtomicU8, Ordering};
+
+    /// How command results are printed.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        Text,
+        /// One JSON document.
+        Json,
+        /// One JSON value per line.
+        Ndjson,
+    }
+
+    static FORMAT: AtomicU8 = AtomicU8::new(Format::Text as u8);
+
+    pub fn set(format: Format) {
+        FORMAT.store(format as u8, Ordering::Relaxed);
+    }
+
+    pub fn get() -> Format {
+        match FORMAT.load(Ordering::Relaxed) {
+            1 => Format::Json,
+            2 => Format::Ndjson,
+            _ => Format::Text,
+        }
+    }
+
+    /// Whether stdout carries JSON, so progress has to go to stderr.
+    pub fn is_machine() -> bool {
+        get() != Format::Text
+    }
+
+    /// A search or info result, tagged with `"source": "aur"` or `"repo"`.
+    #[derive(Debug, Serialize)]
+    #[serde(tag = "source", rename_all = "lowercase")]
+    pub enum Package {
+        Aur(AurPackage),
+        Repo(RepoPackage),
+    }
+
+    /// An available update as printed by `outdated`.
+    #[derive(Debug, Serialize)]
+    pub struct Update {
+        /// `"aur"` or `"repo"`.
+        pub source: &'static str,
+        pub name: String,
+        pub installed: String,
+        pub version: String,
+    }
+
+    #[derive(Debug, Serialize)]
+    pub struct Entry {
+        #[serde(flatten)]
+        pub package: Package,
+        /// Installed version, if any.
+        pub installed: Option<String>,
+    }
+
+    fn encode<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+        let encoded = match get() {
+            Format::Json => serde_json::to_string_pretty(value),
+            _ => serde_json::to_string(value),
+        };
+        encoded.map_err(|e| e.to_string().into())
+    }
+
+    /// Prints `items` as a JSON array, or one item per line for NDJSON.
+    pub fn list<T: Serialize>(items: &[T]) -> Result<()> {
+        if get() == Format::Ndjson {
+            for item in items {
+                println!("{}", encode(item)?);
+            }
+        } else {
+            println!("{}", encode(items)?);
+        }
+        Ok(())
+    }
+
+    /// Prints a single JSON document, on one line for NDJSON.
+    pub fn document<T: Serialize>(value: &T) -> Result<()> {
+        println!("{}", encode(value)?);
+        Ok(())
+    }
+}
+
+mod actions {
+    use super::*;
+
+    pub async fn search_packages(client: &Client, query: &str) -> Result<()> {
+        // Process AUR results
+        let aur_response = aur::search(client, query).await?;
+        let mut aur_packages = aur_response.results.unwrap_or_default();
+
+        if output::is_machine() {
+            // Most voted first, then the repositories in pacman's order.
+            aur_packages.sort_by_key(|pkg| std::cmp::Reverse(pkg.num_votes));
+            let installed = pacman::installed_versions()?;
+            let version = |name: &str| installed.get(name).cloned();
+            let mut entries: Vec<output::Entry> = aur_packages.into_iter()
+                .map(|pkg| output::Entry { installed: version(&pkg.name), package: output::Package::Aur(pkg) })
+                .collect();
+            entries.extend(pacman::search_repo(query).into_iter()
+                .map(|pkg| output::Entry { installed: version(&pkg.name), package: output::Package::Repo(pkg) }));
+            return output::list(&entries);
+        }
+
+        // Sort by votes - ascending order (least votes first)
+        aur_packages.sort_by_key(|pkg| pkg.num_votes);
+
+        // Get official packages
+        let official_packages = pacman::search(query);
+
+        // Count official packages to determine numbering
+        let mut official_count = 0;
+        let mut lines = official_packages.iter().peekable();
+        while let Some(line) = lines.peek() {
+            if !line.starts_with(char::is_whitespace) && line.find('[').is_some() {
+                official_count += 1;
+            }
+            lines.next();
+        }
+
+        // Set starting index for AUR packages (total packages count)
+        let total_packages = aur_packages.len() + official_count;
+        let mut index = total_packages;
+
+        // Display AUR packages with decreasing indices
+        for pkg in &aur_packages {
+            display::print_package(index, pkg);
+            index -= 1;
+        }
+
+        // Reset lines iterator and display official packages with continuing indices
+        index = official_count;
+        let mut lines = official_packages.iter().peekable();
+        while let Some(line) = lines.next() {
+            if !line.starts_with(char::is_whitespace) && line.find('[').is_some() {
+                let description = lines
+                    .next()
+                    .filter(|desc_line| desc_line.starts_with(char::is_whitespace))
+                    .map(|desc_line| desc_line.trim());
+
+                display::print_official_pkg(index, line, description);
+                index -= 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Shows details of packages, preferring the repositories like `install` does.
+    pub async fn show_info(client: &Client, names: &[&str]) -> Result<()> {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let installed = pacman::installed_versions()?;
+        let repo_packages = pacman::repo_info(&names)?;
+        let rest: Vec<String> = names.iter()
+            .filter(|name| !repo_packages.iter().any(|pkg| pkg.name == **name))
+            .cloned()
+            .collect();
+        let aur_packages = aur::info(client, &rest).await?;
+
+        let mut entries = Vec::new();
+        let mut missing = Vec::new();
+        for name in &names {
+            let package = if let Some(pkg) = repo_packages.iter().find(|pkg| pkg.name == *name) {
+                output::Package::Repo(pkg.clone())
+            } else if let Some(pkg) = aur_packages.iter().find(|pkg| pkg.name == *name) {
+                output::Package::Aur(pkg.clone())
+            } else {
+                missing.push(name.as_str());
+                continue;
+            };
+            entries.push(output::Entry { package, installed: installed.get(name).cloned() });
+        }
+
+        if output::is_machine() {
+            output::list(&entries)?;
+        } else {
+            for (i, entry) in entries.iter().enumerate() {
+                if i > 0 {
+                    println!();
+                }
+                display::print_info(entry);
+            }
+        }
+
+        if !missing.is_empty() {
+            return Err(format!("Package(s) not found: {}", missing.join(", ")).into());
+        }
+        Ok(())
+    }
+
+    /// Prints a plan that won't be carried out, as text or JSON.
+    fn print_dry_run(plan: &plan::Plan) -> Result<()> {
+        if output::is_machine() {
+            return output::document(plan);
+        }
+        display::print_plan(plan);
+        println!("\nDry run: nothing was changed.");
+        Ok(())
+    }
+
+    pub async fn install_package(client: &Client, targets: &[&str], opts: build::BuildOptions, dry_run: bool) -> Result<()> {
+        if dry_run {
+            return install_targets(client, targets, opts, true).await;
+        }
+        let before = pacman::installed_versions()?;
+        let result = install_targets(client, targets, opts, false).await;
+        history::record_or_warn("install", &before);
+        result
+    }
+
+    /// Shows search results for `query` and lets the user pick any of them.
+    /// Returns whether each pick is an AUR package and its name, or `None` on 'back'.
+    async fn pick_from_search(client: &Client, query: &str) -> Result<Option<Vec<(bool, String)>>> {
+        // Search for packages
+        let aur_response = aur::search(client, query).await?;
+        let mut aur_packages = aur_response.results.unwrap_or_default();
+        let official_packages = pacman::search(query);
+
+        // Sort AUR packages by votes (ascending - least to most voted)
+        aur_packages.sort_by_key(|pkg| pkg.num_votes);
+
+        // Build combined package list
+        let mut all_packages = Vec::new();
+
+        // Count official packages
+        let mut official_count = 0;
+        for line in official_packages.iter().filter(|line| !line.starts_with(char::is_whitespace)) {
+            if line.find('[').is_some() {
+                official_count += 1;
+            }
+        }
+
+        // Add AUR packages (reversed index order)
+        let total_packages = aur_packages.len() + official_count;
+        for (i, pkg) in aur_packages.iter().enumerate() {
+            let index = total_packages - i;
+            all_packages.push((true, pkg.name.clone(), pkg.version.clone(), index));
+        }
+
+        // Add official packages
+        let mut curr_index = official_count;
+        for line in official_packages.iter().filter(|line| !line.starts_with(char::is_whitespace)) {
+            if let Some(repo_start) = line.find('[') {
+                let parts: Vec<&str> = line[..repo_start].split_whitespace().collect();
+                if !parts.is_empty() {
+                    let name = parts[0].to_string();
+                    let version = parts.get(1).map(|&v| v.to_string()).unwrap_or_default();
+                    all_packages.push((false, name, version, curr_index));
+                    curr_index -= 1;
+                }
+            }
+        }
+
+        if all_packages.is_empty() {
+            return Err(format!("No packages found for {}", query).into());
+        }
+
+        // Display packages
+        println!("Found {} package(s):", all_packages.len());
+        for (is_aur, name, version, index) in &all_packages {
+            let source = if *is_aur { "AUR" } else { "repo" };
+            let installed = if pacman::is_installed(name) { " (Installed)" } else { "" };
+            println!("{}. {} ({}) [{}]{}", index, name, version, source, installed);
+        }
+
+        // Get user selection
+        println!("\nEnter package numbers to install (e.g. '1 3-5 ^4', 'aur', 'extra'),");
+        println!("or type 'back' to cancel:");
+        let items: Vec<(usize, &str)> = all_packages.iter()
+            .map(|(is_aur, name, _, index)| {
+                let source = if *is_aur { "aur" } else { name.split_once('/').map_or("repo", |(repo, _)| repo) };
+                (*index, source)
+            })
+            .collect();
+        let Some(selected) = selection::prompt(&items, false)? else {
+            return Ok(None);
+        };
+
+        Ok(Some(all_packages.iter()
+            .filter(|(_, _, _, index)| selected.contains(index))
+            .map(|(is_aur, name, _, _)| (*is_aur, name.clone()))
+            .collect()))
+    }
+
+    /// Sorts install targets into repository and AUR packages. `aur/name` and
+    /// `repo/name` pick the source; plain names prefer the repositories. Names
+    /// that match nothing exactly fall back to an interactive search.
+    async fn resolve_targets(client: &Client, targets: &[&str]) -> Result<(Vec<String>, Vec<String>)> {
+        let mut repo = Vec::new();
+        let mut aur_names = Vec::new();
+        let mut candidates = Vec::new();
+
+        for target in targets {
+            match target.split_once('/') {
+                Some(("aur", name)) => candidates.push(name.to_string()),
+                Some(_) => repo.push(target.to_string()),
+                None if pacman::in_sync_db(target) => repo.push(target.to_string()),
+                None => candidates.push(target.to_string()),
+            }
+        }
+
+        let found = aur::info(client, &candidates).await?;
+        let mut unmatched = Vec::new();
+        for name in candidates {
+            if found.iter().any(|pkg| pkg.name == name) {
+                aur_names.push(name);
+            } else {
+                unmatched.push(name);
+            }
+        }
+
+        // Repository targets with an explicit repo still have to exist there.
+        repo.retain(|target| {
+            let exists = !target.contains('/') || pacman::in_sync_db(target);
+            if !exists {
+                unmatched.push(target.rsplit('/').next().unwrap_or(target).to_string());
+            }
+            exists
+        });
+
+        for name in unmatched {
+            // Searching is interactive, which JSON output can't be.
+            if output::is_machine() {
+                return Err(format!("No package named {}", name).into());
+            }
+            println!("\nNo package named {}, searching instead...", name);
+            match pick_from_search(client, &name).await? {
+                Some(picks) => {
+                    for (is_aur, picked) in picks {
+                        if is_aur {
+                            aur_names.push(picked);
+                        } else {
+                            repo.push(picked);
+                        }
+                    }
+                }
+                None => println!("Skipping {}.", name),
+            }
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        repo.retain(|name| seen.insert(name.clone()));
+        aur_names.retain(|name| seen.insert(name.clone()));
+        Ok((repo, aur_names))
+    }
+
+    async fn install_targets(client: &Client, targets: &[&str], opts: build::BuildOptions, dry_run: bool) -> Result<()> {
+        let (repo, aur_names) = resolve_targets(client, targets).await?;
+        if repo.is_empty() && aur_names.is_empty() {
+            return Ok(());
+        }
+
+        let mut plan = if aur_names.is_empty() {
+            plan::Plan::default()
+        } else {
+            plan::resolve(client, &aur_names, opts.chroot).await?
+        };
+        if !repo.is_empty() {
+            let installed = pacman::installed_versions()?;
+            plan.repo_targets = pacman::sync_targets(&repo)?
+                .into_iter()
+                .map(|(name, version)| plan::PlanPackage { installed: installed.get(&name).cloned(), name, version })
+                .collect();
+        }
+
+        if dry_run {
+            return print_dry_run(&plan);
+        }
+        display::print_plan(&plan);
+
+        if !plan.conflicts.is_empty() {
+            plan.remove_conflicts = confirm("Remove the conflicting packages as part of this transaction?", false)?;
+        }
+        if !confirm("Proceed with installation?", true)? {
+            return Ok(());
+        }
+
+        // Repository packages go first since AUR packages may build against them.
+        if !repo.is_empty() {
+            pacman::ensure_synced()?;
+            let status = Command::new("sudo")
+                .args(["pacman", "-S"])
+                .args(&repo)
+                .status()?;
+
+            if !status.success() {
+                return Err(format!("Failed to install {}", repo.join(", ")).into());
+            }
+        }
+
+        if !aur_names.is_empty() {
+            build::run_plan(&plan, opts)
+                .await
+                .map_err(|e| format!("Failed to install {}: {}", aur_names.join(", "), e))?;
+        }
+
+        let mut names = repo;
+        names.extend(aur_names);
+        println!("Package(s) {} installed successfully.", names.join(", "));
+        Ok(())
+    }
+
+    /// Which side of the system `update` touches.
+    #[derive(Debug, Clone, Copy)]
+    pub struct UpdateScope {
+        pub repo: bool,
+        pub aur: bool,
+    }
+
+    pub async fn update_packages(client: &Client, opts: build::BuildOptions, dry_run: bool, scope: UpdateScope) -> Result<()> {
+        if dry_run {
+            return update_selected(client, opts, true, scope).await.map(|_| ());
+        }
+        let before = pacman::installed_versions()?;
+        let result = update_selected(client, opts, false, scope).await;
+        history::record_or_warn("update", &before);
+        if let Ok(false) = result {
+            let written = std::fs::create_dir_all(paths::state_dir())
+                .and_then(|()| std::fs::write(paths::last_update_file(), clock::unix_now().to_string()));
+            if let Err(e) = written {
+                eprintln!("Warning: could not record the time of this update: {}", e);
+            }
+        }
+        result.map(|_| ())
+    }
+
+    /// Installed foreign packages as the AUR sees them.
+    struct ForeignCheck {
+        /// Installed foreign packages as (name, version).
+        installed: Vec<(String, String)>,
+        /// Their AUR entries.
+        found: Vec<AurPackage>,
+        /// Names the AUR doesn't know.
+        missing: Vec<String>,
+    }
+
+    impl ForeignCheck {
+        /// Newer AUR versions as (name, installed, available).
+        fn updates(&self) -> Vec<(String, String, String)> {
+            self.found.iter()
+                .filter_map(|aur_pkg| {
+                    let (_, local_ver) = self.installed.iter().find(|(name, _)| *name == aur_pkg.name)?;
+                    let (v_local, v_aur) = (Version::from(local_ver)?, Version::from(&aur_pkg.version)?);
+                    (v_local < v_aur).then(|| (aur_pkg.name.clone(), local_ver.clone(), aur_pkg.version.clone()))
+                })
+                .collect()
+        }
+    }
+
+    /// Looks up installed foreign `packages`, given as (name, version), in the AUR.
+    async fn check_foreign(client: &Client, packages: Vec<(String, String)>) -> Result<ForeignCheck> {
+        // The metadata dump answers everything at once.
+        if aur::is_offline() {
+            let names: Vec<String> = packages.iter().map(|(name, _)| name.clone()).collect();
+            let found = aur::info(client, &names).await?;
+            let missing = names.into_iter().filter(|name| !found.iter().any(|pkg| pkg.name == *name)).collect();
+            return Ok(ForeignCheck { installed: packages, found, missing });
+        }
+
+        // Create chunks for bulk RPC requests
+        let chunk_size = 50; // AUR allows up to 50 packages per request
+        let packages_chunks: Vec<Vec<String>> = packages
+            .chunks(chunk_size)
+            .map(|chunk| chunk.iter().map(|(name, _)| name.clone()).collect())
+            .collect();
+
+        // Process chunks in parallel
+        let results = stream::iter(packages_chunks)
+            .map(|chunk| async move {
+                let response = aur::info(client, &chunk).await;
+                (chunk, response)
+            })
+            .buffer_unordered(4)
+            .collect::<Vec<_>>()
+            .await;
+
+        // A chunk that wasn't answered would look like "no updates", so fail instead.
+        let mut found = Vec::new();
+        let mut missing = Vec::new();
+        let mut failed = Vec::new();
+        for (chunk, response) in results {
+            match response {
+                Ok(aur_packages) => {
+                    missing.extend(chunk.into_iter().filter(|name| !aur_packages.iter().any(|pkg| pkg.name == *name)));
+                    found.extend(aur_packages);
+                }
+                Err(e) => failed.push((chunk.len(), e)),
+            }
+        }
+        if let Some((_, error)) = failed.first() {
+            let count: usize = failed.iter().map(|(len, _)| len).sum();
+            return Err(format!("Could not check {} of {} AUR package(s): {}", count, packages.len(), error).into());
+        }
+
+        Ok(ForeignCheck { installed: packages, found, missing })
+    }
+
+    /// Checks installed AUR packages for updates, reporting anything odd along
+    /// the way. Ignored and pinned packages are listed but not returned.
+    async fn aur_updates(client: &Client) -> Result<Vec<(String, String, String)>> {
+        let packages = pacman::get_installed_aur_packages()?;
+        if packages.is_empty() {
+            status!("No AUR packages installed.");
+            return Ok(Vec::new());
+        }
+
+        status!("Checking {} AUR package(s)...", packages.len());
+        let check = check_foreign(client, packages).await?;
+        report_foreign_status(client, &check.missing, &check.found).await;
+        let updates_available = check.updates();
+
+        // Ignored and pinned packages are shown but never offered.
+        let pins = pins::load()?;
+        let (held, updates_available): (Vec<_>, Vec<_>) = updates_available.into_iter()
+            .partition(|(name, _, _)| config::get().is_ignored(name) || pins.contains_key(name));
+        if !held.is_empty() {
+            status!("\nIgnored updates:");
+            for (name, current, new) in &held {
+                let reason = match pins.get(name) {
+                    Some(pin) => format!("pinned at {}", pin.version),
+                    None => "IgnorePkg".to_string(),
+                };
+                status!("  {} ({} → {}) [{}]", name, current, new, reason);
+            }
+        }
+
+        if updates_available.is_empty() {
+            status!("No updates available for AUR packages.");
+        }
+        Ok(updates_available)
+    }
+
+    /// Exit status of `outdated` when updates are available.
+    pub const OUTDATED_EXIT: i32 = 100;
+
+    /// Lists available updates without syncing or changing anything. Returns
+    /// whether there are any.
+    pub async fn outdated(client: &Client, scope: UpdateScope) -> Result<bool> {
+        let mut updates = Vec::new();
+        if scope.aur {
+            updates.extend(aur_updates(client).await?.into_iter()
+                .map(|(name, installed, version)| output::Update { source: "aur", name, installed, version }));
+        }
+        if scope.repo {
+            updates.extend(pacman::pending_upgrades()?.into_iter()
+                .map(|(name, installed, version)| output::Update { source: "repo", name, installed, version }));
+        }
+
+        if output::is_machine() {
+            output::list(&updates)?;
+        } else if updates.is_empty() {
+            println!("\nEverything is up to date.");
+        } else {
+            println!("\n{} update(s) available:", updates.len());
+            for update in &updates {
+                let source = if update.source == "aur" { "AUR" } else { "repo" };
+                println!("  {} ({} → {}) [{}]", update.name, update.installed, update.version, source);
+            }
+        }
+        Ok(!updates.is_empty())
+    }
 
-            // Process results and find updates
-            for result in results {
-                if let Ok(response) = result {
-                    if let Some(aur_packages) = response.results {
-                        for aur_pkg in aur_packages {
-                            if let Some((_, local_ver)) = packages.iter()
-                                .find(|(name, _)| name == &aur_pkg.name)
-                            {
-                                if let (Some(v_local), Some(v_aur)) =
-                                    (Version::from(local_ver), Version::from(&aur_pkg.version)) {
-                                    if v_local < v_aur {
-                                        updates_available.push((
-                                            aur_pkg.name,
-                                            local_ver.clone(),
-                                            aur_pkg.version
-                                        ));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+    /// Writes package state in the Prometheus text format to `textfile`
+    /// (for node_exporter's textfile collector), or to stdout.
+    pub async fn metrics(client: &Client, textfile: Option<&str>) -> Result<()> {
+        let check = check_foreign(client, pacman::get_installed_aur_packages()?).await?;
+        let pins = pins::load()?;
+        let outdated = check.updates().into_iter()
+            .filter(|(name, _, _)| !config::get().is_ignored(name) && !pins.contains_key(name))
+            .count();
+
+        let mut metrics = vec![
+            ("aurorus_foreign_packages", "Installed packages not in any sync repository.", check.installed.len() as u64),
+            ("aurorus_aur_outdated_packages", "Foreign packages with a newer version in the AUR, not counting ignored or pinned ones.", outdated as u64),
+            ("aurorus_aur_flagged_packages", "Foreign packages flagged out-of-date in the AUR.", check.found.iter().filter(|pkg| pkg.out_of_date.is_some()).count() as u64),
+            ("aurorus_aur_orphaned_packages", "Foreign packages without a maintainer in the AUR.", check.found.iter().filter(|pkg| pkg.maintainer.is_none()).count() as u64),
+            ("aurorus_aur_missing_packages", "Foreign packages that are not in the AUR.", check.missing.len() as u64),
+        ];
+        let last_update = std::fs::read_to_string(paths::last_update_file()).ok()
+            .and_then(|contents| contents.trim().parse::<u64>().ok());
+        if let Some(time) = last_update {
+            metrics.push(("aurorus_last_update_timestamp_seconds", "When aurorus update last finished without errors and was not cancelled.", time));
+        }
+
+        let mut text = String::new();
+        for (name, help, value) in metrics {
+            text.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value));
+        }
+
+        let Some(textfile) = textfile else {
+            print!("{}", text);
+            return Ok(());
+        };
+        // Write then rename so the collector never reads a partial file.
+        let partial = format!("{}.{}", textfile, std::process::id());
+        std::fs::write(&partial, text)?;
+        std::fs::rename(&partial, textfile)?;
+        Ok(())
+    }
+
+    /// Returns whether the user cancelled at the selection.
+    async fn update_selected(client: &Client, opts: build::BuildOptions, dry_run: bool, scope: UpdateScope) -> Result<bool> {
+        if dry_run {
+            let names: Vec<String> = if scope.aur {
+                aur_updates(client).await?.into_iter().map(|(name, _, _)| name).collect()
+            } else {
+                Vec::new()
+            };
+            let mut plan = plan::resolve(client, &names, opts.chroot).await?;
+            if scope.repo {
+                plan.repo_upgrades = pacman::pending_upgrades()?
+                    .into_iter()
+                    .map(|(name, old, new)| plan::PlanPackage { name, version: new, installed: Some(old) })
+                    .collect();
             }
+            print_dry_run(&plan)?;
+            return Ok(false);
+        }
 
-            if updates_available.is_empty() {
-                println!("No updates available for AUR packages.");
+        // Upgrade official packages first so AUR packages build against
+        // current libraries instead of being broken by the sync afterwards.
+        if scope.repo {
+            println!("\nUpdating official packages via pacman...");
+            let status = Command::new("sudo").args(["pacman", "-Syu"]).status()?;
+
+            if status.success() {
+                println!("Official packages updated successfully.");
+            } else if scope.aur {
+                return Err("Failed to update official packages; not building AUR packages on a partially upgraded system".into());
             } else {
-                // Display available updates
-                println!("\nUpdates available for {} package(s):", updates_available.len());
-                for (i, (pkg, current, new)) in updates_available.iter().enumerate() {
-                    println!("{}. {} ({} → {})", i + 1, pkg, current, new);
-                }
+                return Err("Failed to update official packages.".into());
+            }
+        }
 
-                // Get user selection
-                println!("\nEnter package numbers to update (e.g., '1 2 3'),");
-                println!("press Enter to update all, or type 'back' to cancel:");
-                let mut input = String::new();
-                io::stdin().read_line(&mut input)?;
-                let input = input.trim();
+        if !scope.aur {
+            return Ok(false);
+        }
 
-                if input.eq_ignore_ascii_case("back") {
-                    return Ok(());
-                }
+        let updates_available = aur_updates(client).await?;
+        if updates_available.is_empty() {
+            return Ok(false);
+        }
 
-                // Determine which packages to update
-                let to_update = if input.is_empty() {
-                    updates_available
-                } else {
-                    input.split_whitespace()
-                        .filter_map(|s| s.parse::<usize>().ok())
-                        .filter(|&n| n > 0 && n <= updates_available.len())
-                        .map(|i| updates_available[i - 1].clone())
-                        .collect()
+        // Display available updates
+        println!("\nUpdates available for {} package(s):", updates_available.len());
+        for (i, (pkg, current, new)) in updates_available.iter().enumerate() {
+            println!("{}. {} ({} → {})", i + 1, pkg, current, new);
+        }
+
+        // Get user selection
+        println!("\nEnter package numbers to update (e.g., '1 2 3', '1-5', '^4'),");
+        println!("press Enter to update all, or type 'back' to cancel:");
+        let items: Vec<(usize, &str)> = (1..=updates_available.len()).map(|n| (n, "aur")).collect();
+        let Some(selected) = selection::prompt(&items, true)? else {
+            return Ok(true);
+        };
+
+        // Build selected packages in dependency order
+        let names: Vec<String> = selected.into_iter()
+            .map(|n| updates_available[n - 1].0.clone())
+            .collect();
+        let mut plan = plan::resolve(client, &names, opts.chroot).await?;
+        display::print_plan(&plan);
+        if !plan.conflicts.is_empty() {
+            plan.remove_conflicts = confirm("Remove the conflicting packages as part of this transaction?", false)?;
+        }
+
+        let update_opts = build::BuildOptions { noconfirm: true, ..opts };
+        build::run_plan(&plan, update_opts).await?;
+        Ok(false)
+    }
+
+    /// Reports foreign packages that left the AUR (and what may have replaced
+    /// them) and ones that are flagged out-of-date or orphaned.
+    async fn report_foreign_status(client: &Client, missing: &[String], found: &[AurPackage]) {
+        let mut notes = Vec::new();
+
+        for name in missing {
+            let note = if let Some(provider) = pacman::repo_provider(name) {
+                format!("not in the AUR, provided by {} in the official repositories", provider)
+            } else if let Some(pkg) = aur::search_by(client, "replaces", name).await.ok().and_then(|r| r.into_iter().next()) {
+                format!("not in the AUR, replaced by {}", pkg.name)
+            } else if let Some(pkg) = aur::search_by(client, "provides", name).await.ok().and_then(|r| r.into_iter().next()) {
+                format!("not in the AUR, possibly merged into {} (which provides it)", pkg.name)
+            } else {
+                "no longer in the AUR".to_string()
+            };
+            notes.push((name.clone(), note));
+        }
+
+        for pkg in found {
+            if let Some(flagged) = pkg.out_of_date {
+                notes.push((pkg.name.clone(), format!("flagged out-of-date since {}", clock::format(flagged))));
+            }
+            if pkg.maintainer.is_none() {
+                notes.push((pkg.name.clone(), "orphaned (no maintainer)".to_string()));
+            }
+        }
+
+        if !notes.is_empty() {
+            status!("\nForeign packages needing attention:");
+            for (name, note) in notes {
+                status!("  {}: {}", name, note);
+            }
+        }
+    }
+
+    pub async fn show_log(client: &Client, package: &str, list: bool, number: Option<usize>) -> Result<()> {
+        let mut entries = logs::entries(package)?;
+        if entries.is_empty() {
+            // Logs are kept per pkgbase, which may differ from the package name.
+            let info = aur::info(client, &[package.to_string()]).await.unwrap_or_default();
+            if let Some(pkgbase) = info.first().and_then(|pkg| pkg.package_base.as_deref()) {
+                entries = logs::entries(pkgbase)?;
+            }
+        }
+
+        if entries.is_empty() {
+            return Err(format!("No build logs for {}", package).into());
+        }
+
+        if list {
+            println!("Builds of {} (newest first):", package);
+            for (i, entry) in entries.iter().enumerate() {
+                let started = entry.started.map_or_else(|| "unknown time".to_string(), clock::format);
+                let result = match &entry.result {
+                    Some((code, secs)) if code == "0" => format!("succeeded in {}s", secs),
+                    Some((code, secs)) => format!("failed with exit status {} after {}s", code, secs),
+                    None => "unfinished".to_string(),
                 };
+                println!("{}. {}  {}", i + 1, started, result);
+                println!("   {}", entry.path.display());
+            }
+            return Ok(());
+        }
+
+        let index = number.unwrap_or(1);
+        let entry = index.checked_sub(1)
+            .and_then(|i| entries.get(i))
+            .ok_or_else(|| format!("There is no build number {} for {}", index, package))?;
 
-                // Update selected packages
-                for (package, _, _) in to_update {
-                    println!("\nUpdating {}...", package);
-                    let pkg_path = aur::clone_package_repo(&package).await?;
+        print!("{}", fs::read_to_string(&entry.path).await?);
+        Ok(())
+    }
 
-                    let status = TokioCommand::new("makepkg")
-                        .args(["-si", "--noconfirm"])
-                        .current_dir(&pkg_path)
-                        .status()
-                        .await?;
+    /// Holds `package` at its installed (or the given) version and the commit
+    /// of its cached AUR clone, so updates skip it until it is unpinned.
+    pub async fn pin(client: &Client, package: &str, version: Option<&str>) -> Result<()> {
+        let installed = pacman::installed_versions()?;
+        let version = match (version, installed.get(package)) {
+            (Some(version), _) => version.to_string(),
+            (None, Some(installed)) => installed.clone(),
+            (None, None) => return Err(format!("Package {} is not installed", package).into()),
+        };
 
-                    if status.success() {
-                        println!("{} updated successfully", package);
-                    } else {
-                        eprintln!("Failed to update {}", package);
+        let pkgbase = aur::info(client, &[package.to_string()]).await.ok()
+            .and_then(|info| info.into_iter().next())
+            .and_then(|pkg| pkg.package_base)
+            .unwrap_or_else(|| package.to_string());
+        let clone = Path::new(&paths::cache_dir()).join(&pkgbase);
+        let commit = Command::new("git")
+            .arg("-C").arg(&clone)
+            .args(["rev-parse", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string());
+
+        let mut pins = pins::load()?;
+        pins.insert(package.to_string(), pins::Pin { version: version.clone(), commit: commit.clone(), time: clock::unix_now() });
+        pins::save(&pins)?;
+
+        match commit {
+            Some(commit) => println!("Pinned {} at {} (commit {}).", package, version, &commit[..commit.len().min(12)]),
+            None => println!("Pinned {} at {}.", package, version),
+        }
+        Ok(())
+    }
+
+    pub fn unpin(package: &str) -> Result<()> {
+        let mut pins = pins::load()?;
+        if pins.remove(package).is_none() {
+            return Err(format!("Package {} is not pinned", package).into());
+        }
+        pins::save(&pins)?;
+        println!("Unpinned {}.", package);
+        Ok(())
+    }
+
+    pub fn list_pins() -> Result<()> {
+        let pins = pins::load()?;
+        if pins.is_empty() {
+            println!("No packages are pinned.");
+        }
+        for (name, pin) in &pins {
+            let commit = pin.commit.as_deref().map_or(String::new(), |c| format!(", commit {}", &c[..c.len().min(12)]));
+            println!("{} at {}{} (since {})", name, pin.version, commit, clock::format(pin.time));
+        }
+
+        let ignored = &config::get().ignore;
+        if !ignored.is_empty() {
+            println!("Ignored by IgnorePkg: {}", ignored.join(" "));
+        }
+        Ok(())
+    }
+
+    pub fn show_history() -> Result<()> {
+        let transactions = history::load()?;
+        if transactions.is_empty() {
+            println!("No transactions recorded yet.");
+            return Ok(());
+        }
+
+        for transaction in &transactions {
+            println!("{}. {} {} ({} package(s))",
+                     transaction.id, clock::format(transaction.time), transaction.action, transaction.changes.len());
+            for change in &transaction.changes {
+                let old = change.old.as_deref().unwrap_or("none");
+                let new = change.new.as_deref().unwrap_or("removed");
+                println!("   {} ({} → {})", change.name, old, new);
+            }
+        }
+        Ok(())
+    }
+
+    /// Reinstalls the package files in `files` and removes `remove`, recording
+    /// the result as a transaction.
+    fn restore(action: &str, files: &[PathBuf], remove: &[String]) -> Result<()> {
+        let before = pacman::installed_versions()?;
+        let mut failed = false;
+
+        if !files.is_empty() {
+            failed |= !Command::new("sudo").args(["pacman", "-U"]).args(files).status()?.success();
+        }
+        if !failed && !remove.is_empty() {
+            failed |= !Command::new("sudo").args(["pacman", "-R"]).args(remove).status()?.success();
+        }
+
+        if let Some(id) = history::record_or_warn(action, &before) {
+            println!("Recorded as transaction {}.", id);
+        }
+        if failed {
+            return Err(format!("{} did not complete", action).into());
+        }
+        Ok(())
+    }
+
+    pub fn rollback(id: u64) -> Result<()> {
+        let transactions = history::load()?;
+        let transaction = transactions.iter()
+            .find(|transaction| transaction.id == id)
+            .ok_or_else(|| format!("No transaction with id {}", id))?;
+        let installed = pacman::installed_versions()?;
+
+        let mut files = Vec::new();
+        let mut remove = Vec::new();
+        let mut unavailable = Vec::new();
+        for change in &transaction.changes {
+            match &change.old {
+                Some(old) if installed.get(&change.name) != Some(old) => {
+                    match history::package_files(&change.name).into_iter().find(|(version, _)| version == old) {
+                        Some((_, path)) => files.push(path),
+                        None => unavailable.push(format!("{} {}", change.name, old)),
                     }
                 }
+                None if installed.contains_key(&change.name) => remove.push(change.name.clone()),
+                _ => {}
             }
+        }
+
+        if files.is_empty() && remove.is_empty() {
+            return Err(if unavailable.is_empty() {
+                format!("Transaction {} is already undone", id)
+            } else {
+                format!("No package files left to roll back transaction {}", id)
+            }.into());
+        }
+
+        println!("Rolling back transaction {} ({}):", id, transaction.action);
+        for file in &files {
+            println!("  reinstall {}", file.display());
+        }
+        for name in &remove {
+            println!("  remove    {}", name);
+        }
+        if !unavailable.is_empty() {
+            println!("No package file found for: {}", unavailable.join(", "));
+        }
+
+        if !confirm("Proceed with rollback?", true)? {
+            return Ok(());
+        }
+        restore("rollback", &files, &remove)
+    }
+
+    pub fn downgrade(package: &str) -> Result<()> {
+        let installed = pacman::installed_versions()?;
+        let current = installed.get(package)
+            .ok_or_else(|| format!("Package {} is not installed", package))?;
+        let current_version = Version::from(current)
+            .ok_or_else(|| format!("Cannot compare version {}", current))?;
+
+        let previous = history::package_files(package)
+            .into_iter()
+            .filter(|(version, _)| Version::from(version).is_some_and(|v| v < current_version))
+            .max_by(|(a, _), (b, _)| {
+                Version::from(a).partial_cmp(&Version::from(b)).unwrap_or(std::cmp::Ordering::Equal)
+            });
+
+        let Some((version, path)) = previous else {
+            return Err(format!("No earlier package file of {} in the archive", package).into());
+        };
+
+        println!("Downgrading {} {} → {} ({})", package, current, version, path.display());
+        if !confirm("Proceed with downgrade?", true)? {
+            return Ok(());
+        }
+        restore("downgrade", &[path], &[])
+    }
+
+    pub fn cache_list() -> Result<()> {
+        let clones = cache::clones()?;
+        if clones.is_empty() {
+            println!("No cached clones in {}", paths::cache_dir());
         } else {
-            println!("No AUR packages installed.");
+            println!("Cached pkgbases in {}:", paths::cache_dir());
+            for clone in &clones {
+                let last_used = clone.last_used.map_or_else(|| "unknown".to_string(), clock::format);
+                println!("  {:<30} {:>12}  last used {}", clone.pkgbase, display::format_size(clone.size), last_used);
+            }
+        }
+
+        let clones_size: u64 = clones.iter().map(|clone| clone.size).sum();
+        let archive_size = cache::size(&paths::archive_dir());
+        let chroot_size = cache::size(&paths::chroot_dir());
+        println!("\nClones:  {}", display::format_size(clones_size));
+        println!("Archive: {}", display::format_size(archive_size));
+        if chroot_size > 0 {
+            // Mostly root-owned, so this undercounts what we can't read.
+            println!("Chroot:  {}", display::format_size(chroot_size));
+        }
+        Ok(())
+    }
+
+    pub fn cache_clean() -> Result<()> {
+        let freed = cache::clean_build_dirs()?;
+        println!("Removed build directories and cached AUR responses, freed {}", display::format_size(freed));
+        Ok(())
+    }
+
+    pub fn cache_prune() -> Result<()> {
+        let unused = cache::unused_clones()?;
+        if unused.is_empty() {
+            println!("Every cached clone belongs to an installed package.");
+            return Ok(());
+        }
+        for clone in &unused {
+            println!("Removing {} ({})", clone.path.display(), display::format_size(clone.size));
+            std::fs::remove_dir_all(&clone.path)?;
+        }
+        let freed: u64 = unused.iter().map(|clone| clone.size).sum();
+        println!("Removed {} clone(s), freed {}", unused.len(), display::format_size(freed));
+        Ok(())
+    }
+
+    pub fn cache_keep(keep: usize) -> Result<()> {
+        let old = cache::old_package_files(keep)?;
+        if old.is_empty() {
+            println!("No package has more than {} built file(s).", keep);
+            return Ok(());
+        }
+
+        println!("Package files beyond the newest {} of each package:", keep);
+        for path in &old {
+            println!("  {}", path.display());
+        }
+        let total: u64 = old.iter().map(|path| cache::size(path)).sum();
+        println!("\nTotal: {}", display::format_size(total));
+        // The archive is what rollback and downgrade reinstall from.
+        if !confirm("Delete them? They can no longer be used to roll back.", false)? {
+            return Ok(());
+        }
+
+        for path in &old {
+            std::fs::remove_file(path)?;
+            let signature = PathBuf::from(format!("{}.sig", path.display()));
+            if signature.exists() {
+                std::fs::remove_file(signature)?;
+            }
+        }
+        println!("Removed {} package file(s), freed {}", old.len(), display::format_size(total));
+        Ok(())
+    }
+
+    pub async fn check_rebuild(client: &Client, opts: build::BuildOptions) -> Result<()> {
+        println!("Checking installed foreign packages for missing libraries...");
+        let broken = rebuild::check()?;
+        if broken.is_empty() {
+            println!("No foreign package needs a rebuild.");
+            return Ok(());
+        }
+
+        println!("\n{} package(s) need a rebuild:", broken.len());
+        for (i, pkg) in broken.iter().enumerate() {
+            println!("{}. {}", i + 1, pkg.package);
+            for reason in &pkg.reasons {
+                println!("     {}", reason);
+            }
+        }
+
+        println!("\nEnter package numbers to rebuild (e.g., '1 2 3', '1-5', '^4'),");
+        println!("press Enter to rebuild all, or type 'back' to cancel:");
+        let items: Vec<(usize, &str)> = (1..=broken.len()).map(|n| (n, "aur")).collect();
+        let Some(selected) = selection::prompt(&items, true)? else {
+            return Ok(());
+        };
+        let names: Vec<String> = selected.into_iter()
+            .map(|n| broken[n - 1].package.clone())
+            .collect();
+
+        let mut plan = plan::resolve(client, &names, opts.chroot).await?;
+        display::print_plan(&plan);
+        if !plan.conflicts.is_empty() {
+            plan.remove_conflicts = confirm("Remove the conflicting packages as part of this transaction?", false)?;
+        }
+
+        let before = pacman::installed_versions()?;
+        let result = build::run_plan(&plan, opts).await;
+        history::record_or_warn("rebuild", &before);
+        result
+    }
+
+    pub fn clean(dry_run: bool) -> Result<()> {
+        let local = pacman::local_packages()?;
+        let unneeded = pacman::unneeded(&local);
+        if unneeded.is_empty() {
+            println!("No unneeded dependencies installed.");
+            return Ok(());
+        }
+
+        // Mark what aurorus itself pulled in, such as make dependencies.
+        let ours: BTreeSet<String> = history::load()?
+            .into_iter()
+            .flat_map(|transaction| transaction.changes)
+            .filter(|change| change.old.is_none())
+            .map(|change| change.name)
+            .collect();
+
+        println!("Dependencies no longer required by any package:");
+        for pkg in &unneeded {
+            let marker = if ours.contains(&pkg.name) { " [installed by aurorus]" } else { "" };
+            println!("  {} {} ({}){}", pkg.name, pkg.version, display::format_size(pkg.size), marker);
+        }
+        let total: u64 = unneeded.iter().map(|pkg| pkg.size).sum();
+        println!("\nTotal reclaimable: {}", display::format_size(total));
+
+        if dry_run {
+            println!("\nDry run: nothing was changed.");
+            return Ok(());
+        }
+        if !confirm(&format!("Remove these {} package(s)?", unneeded.len()), true)? {
+            return Ok(());
         }
 
-        // Update official packages
-        println!("\nUpdating official packages via pacman...");
-        let status = Command::new("sudo").args(["pacman", "-Syu"]).status()?;
+        let before = pacman::installed_versions()?;
+        let status = Command::new("sudo")
+            .args(["pacman", "-Rns", "--noconfirm"])
+            .args(unneeded.iter().map(|pkg| &pkg.name))
+            .status()?;
+        history::record_or_warn("clean", &before);
 
         if status.success() {
-            println!("Official packages updated successfully.");
+            println!("Removed {} package(s)", unneeded.len());
+            Ok(())
         } else {
-            eprintln!("Failed to update official packages.");
+            Err("Failed to remove unneeded dependencies".into())
         }
+    }
 
-        Ok(())
+    /// Prints the packages that need `name`, and what needs those, as a tree.
+    fn print_dependents(name: &str, required_by: &BTreeMap<String, Vec<String>>, depth: usize) {
+        for dependent in required_by.get(name).into_iter().flatten() {
+            println!("{}└ {}", "  ".repeat(depth + 1), dependent);
+            print_dependents(dependent, required_by, depth + 1);
+        }
     }
 
-    pub fn uninstall_package(package: &str) -> Result<()> {
-        if !pacman::is_installed(package) {
-            return Err(format!("Package {} is not installed", package).into());
+    pub fn uninstall_package(packages: &[&str], dry_run: bool) -> Result<()> {
+        let local = pacman::local_packages()?;
+        if let Some(missing) = packages.iter().find(|name| !local.iter().any(|pkg| pkg.name == **name)) {
+            return Err(format!("Package {} is not installed", missing).into());
+        }
+
+        // Follow dependents until nothing else would break.
+        let mut removing: BTreeSet<String> = packages.iter().map(|name| name.to_string()).collect();
+        let mut required_by: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        loop {
+            let broken = pacman::broken_by(&local, &removing);
+            if broken.is_empty() {
+                break;
+            }
+            for (dependent, needed) in broken {
+                removing.insert(dependent.clone());
+                required_by.entry(needed).or_default().push(dependent);
+            }
+        }
+
+        if !required_by.is_empty() {
+            println!("Other installed packages depend on what you are removing:");
+            let mut shown = BTreeSet::new();
+            for package in packages {
+                if required_by.contains_key(*package) && shown.insert(*package) {
+                    println!("{}", package);
+                    print_dependents(package, &required_by, 0);
+                }
+            }
+            let cascade = removing.iter().filter(|name| !packages.contains(&name.as_str())).count();
+            if dry_run {
+                println!("\nThe {} dependent package(s) would have to be removed as well.", cascade);
+            } else if !confirm(&format!("Remove the {} dependent package(s) as well?", cascade), false)? {
+                return Err("Not removing packages other installed packages depend on".into());
+            }
+        }
+
+        let targets: Vec<String> = removing.into_iter().collect();
+        let removal = pacman::removal_targets(&targets)?;
+        println!("\nPackages to remove:");
+        let mut freed = 0;
+        for name in &removal {
+            let pkg = local.iter().find(|pkg| pkg.name == *name);
+            let size = pkg.map_or(0, |pkg| pkg.size);
+            freed += size;
+            let note = if targets.contains(name) { "" } else { " [no longer needed]" };
+            println!("  {} {} ({}){}", name, pkg.map_or("", |pkg| pkg.version.as_str()), display::format_size(size), note);
+        }
+        println!("\nTotal freed: {}", display::format_size(freed));
+
+        if dry_run {
+            println!("\nDry run: nothing was changed.");
+            return Ok(());
+        }
+        if !confirm(&format!("Remove these {} package(s)?", removal.len()), true)? {
+            return Ok(());
         }
 
+        let before = pacman::installed_versions()?;
         let status = Command::new("sudo")
-            .args(["pacman", "-Rns", package])
+            .args(["pacman", "-Rns", "--noconfirm"])
+            .args(&targets)
             .status()?;
+        history::record_or_warn("remove", &before);
 
         if status.success() {
-            println!("Package {} removed successfully", package);
+            println!("Removed {} package(s), freed {}", removal.len(), display::format_size(freed));
             Ok(())
         } else {
-            Err(format!("Failed to remove package {}", package).into())
+            Err(format!("Failed to remove {}", targets.join(" ")).into())
         }
     }
 }
 
+/// Asks a yes/no question; an empty answer picks `default`. At the end of
+/// input nobody can answer, so that is a no.
+fn confirm(question: &str, default: bool) -> Result<bool> {
+    println!("\n{} {}", question, if default { "[Y/n]" } else { "[y/N]" });
+    let mut input = String::new();
+    if io::stdin().read_line(&mut input)? == 0 {
+        return Ok(false);
+    }
+    let input = input.trim();
+    if input.is_empty() {
+        return Ok(default);
+    }
+    Ok(input.eq_ignore_ascii_case("y") || input.eq_ignore_ascii_case("yes"))
+}
+
+/// Removes `flag` from `args`, returning whether it was present.
+fn take_flag(args: &mut Vec<&str>, flag: &str) -> bool {
+    let before = args.len();
+    args.retain(|arg| *arg != flag);
+    args.len() != before
+}
+
+/// Runs one command line, either typed at the prompt or given on the command
+/// line. Returns the exit status for direct invocation.
+async fn run_command(client: &Client, command: &str, mut args: Vec<&str>) -> Result<i32> {
+    let build_opts = build::BuildOptions {
+        chroot: take_flag(&mut args, "--chroot"),
+        ..Default::default()
+    };
+    let dry_run = take_flag(&mut args, "--dry-run");
+    if dry_run && !matches!(command, "install" | "i" | "update" | "up" | "uninstall" | "ui" | "clean" | "autoremove") {
+        return Err(AurorusError::Usage("--dry-run works with install, update, uninstall, and clean".into()));
+    }
+    aur::set_offline(take_flag(&mut args, "--offline") || config::get().offline);
+    responses::set_refresh(take_flag(&mut args, "--refresh"));
+    let (json, ndjson) = (take_flag(&mut args, "--json"), take_flag(&mut args, "--ndjson"));
+    let format = match (json, ndjson) {
+        (_, true) => output::Format::Ndjson,
+        (true, false) => output::Format::Json,
+        _ => output::Format::Text,
+    };
+    output::set(format);
+    let has_json = matches!(command, "search" | "s" | "info" | "outdated")
+        || (dry_run && matches!(command, "install" | "i" | "update" | "up"));
+    if format != output::Format::Text && !has_json {
+        return Err(AurorusError::Usage("--json and --ndjson work with search, info, outdated, and install or update with --dry-run".into()));
+    }
+
+    match command {
+        "help" => display::print_help(),
+
+        "search" | "s" => {
+            if args.is_empty() {
+                return Err(AurorusError::Usage("search <package> or s <package>".into()));
+            }
+            actions::search_packages(client, &args.join(" ")).await?;
+        },
+
+        "info" => {
+            if args.is_empty() {
+                return Err(AurorusError::Usage("info [--json|--ndjson] <package>...".into()));
+            }
+            actions::show_info(client, &args).await?;
+        },
+
+        "install" | "i" => {
+            if args.is_empty() {
+                return Err(AurorusError::Usage("install [--chroot] [--dry-run] <package>... or i <package>...".into()));
+            }
+            actions::install_package(client, &args, build_opts, dry_run).await?;
+        },
+
+        "uninstall" | "ui" => {
+            if args.is_empty() {
+                return Err(AurorusError::Usage("uninstall [--dry-run] <package>... or ui <package>...".into()));
+            }
+            actions::uninstall_package(&args, dry_run)?;
+        },
+
+        "log" => {
+            let usage = || AurorusError::Usage("log [--list] <package> [n]".into());
+            let list = take_flag(&mut args, "--list");
+            let number = match args.get(1) {
+                Some(n) => Some(n.parse::<usize>().map_err(|_| usage())?),
+                None => None,
+            };
+            let package = args.first().ok_or_else(usage)?;
+            actions::show_log(client, package, list, number).await?;
+        },
+
+        "history" => actions::show_history()?,
+
+        "pin" => match args.as_slice() {
+            [] => actions::list_pins()?,
+            [package] => actions::pin(client, package, None).await?,
+            [package, version] => actions::pin(client, package, Some(version)).await?,
+            _ => return Err(AurorusError::Usage("pin [<package> [version]]".into())),
+        },
+
+        "unpin" => {
+            let package = args.first().ok_or_else(|| AurorusError::Usage("unpin <package>".into()))?;
+            actions::unpin(package)?;
+        },
+
+        "rollback" => {
+            let Some(Ok(id)) = args.first().map(|id| id.parse::<u64>()) else {
+                return Err(AurorusError::Usage("rollback <id>".into()));
+            };
+            actions::rollback(id)?;
+        },
+
+        "downgrade" => {
+            let package = args.first().ok_or_else(|| AurorusError::Usage("downgrade <package>".into()))?;
+            actions::downgrade(package)?;
+        },
+
+        "clean" | "autoremove" => actions::clean(dry_run)?,
+
+        "cache" => match args.as_slice() {
+            [] | ["list"] => actions::cache_list()?,
+            ["clean"] => actions::cache_clean()?,
+            ["prune"] => actions::cache_prune()?,
+            ["keep", n] => {
+                let keep = n.parse::<usize>().map_err(|_| AurorusError::Usage("cache keep <n>".into()))?;
+                actions::cache_keep(keep)?;
+            },
+            _ => return Err(AurorusError::Usage("cache [list | clean | prune | keep <n>]".into())),
+        },
+
+        "checkrebuild" => actions::check_rebuild(client, build_opts).await?,
+
+        "update" | "up" => {
+            let (repo, aur) = (take_flag(&mut args, "--repo"), take_flag(&mut args, "--aur"));
+            // Neither flag means both.
+            let scope = actions::UpdateScope { repo: repo || !aur, aur: aur || !repo };
+            actions::update_packages(client, build_opts, dry_run, scope).await?;
+        },
+
+        "metrics" => match args.as_slice() {
+            [] => actions::metrics(client, None).await?,
+            ["--textfile", path] => actions::metrics(client, Some(path)).await?,
+            _ => return Err(AurorusError::Usage("metrics [--textfile <path>]".into())),
+        },
+
+        "outdated" => {
+            let (repo, aur) = (take_flag(&mut args, "--repo"), take_flag(&mut args, "--aur"));
+            let scope = actions::UpdateScope { repo: repo || !aur, aur: aur || !repo };
+            if actions::outdated(client, scope).await? {
+                return Ok(actions::OUTDATED_EXIT);
+            }
+        },
+
+        _ => return Err("Unknown command. Type 'help' to see available commands.".into()),
+    }
+
+    Ok(0)
+}
+
+fn report(error: &AurorusError) {
+    match error {
+        AurorusError::Usage(_) => println!("{}", error),
+        _ => eprintln!("Error: {}", error),
+    }
+}
+
 #[tokio::main]
 async fn main() -> std::result::Result<(), Box<dyn StdError>> {
+    config::load()?;
+
+    let client = http::client()?;
+
+    // With arguments, run that single command and exit, e.g. `aurorus update --dry-run`.
+    let argv: Vec<String> = env::args().skip(1).collect();
+    if let Some((command, args)) = argv.split_first() {
+        let args = args.iter().map(|arg| arg.as_str()).collect();
+        match run_command(&client, &command.to_lowercase(), args).await {
+            Ok(0) => return Ok(()),
+            Ok(code) => std::process::exit(code),
+            Err(e) => {
+                report(&e);
+                std::process::exit(1);
+            }
+        }
+    }
+
     println!("Welcome to aurorus!");
     println!("Type 'help' for a list of commands.\n");
 
-    let client = Client::new();
-    // Removed unused 'commands' variable
-
     loop {
         // Read user input
         print!("aurorus> ");
@@ -601,55 +4543,14 @@ async fn main() -> std::result::Result<(), Box<dyn StdError>> {
         let command = parts.next().unwrap().to_lowercase();
         let args: Vec<&str> = parts.collect();
 
-        // Execute command
-        match command.as_str() {
-            "exit" => break,
-
-            "help" => display::print_help(),
-
-            "search" | "s" => {
-                if args.is_empty() {
-                    println!("Usage: search <package> or s <package>");
-                    continue;
-                }
-                let query = args.join(" ");
-                if let Err(e) = actions::search_packages(&client, &query).await {
-                    eprintln!("Error: {}", e);
-                }
-            },
-
-            "install" | "i" => {
-                if args.is_empty() {
-                    println!("Usage: install <package> or i <package>");
-                    continue;
-                }
-                let query = args.join(" ");
-                if let Err(e) = actions::install_package(&client, &query).await {
-                    eprintln!("Error: {}", e);
-                }
-            },
-
-            "uninstall" | "ui" => {
-                if args.is_empty() {
-                    println!("Usage: uninstall <package> or ui <package>");
-                    continue;
-                }
-                let package = args.join(" ");
-                if let Err(e) = actions::uninstall_package(&package) {
-                    eprintln!("Error: {}", e);
-                }
-            },
-
-            "update" | "up" => {
-                if let Err(e) = actions::update_packages(&client).await {
-                    eprintln!("Error: {}", e);
-                }
-            },
-
-            _ => println!("Unknown command. Type 'help' to see available commands."),
+        if command == "exit" {
+            break;
+        }
+        if let Err(e) = run_command(&client, &command, args).await {
+            report(&e);
         }
     }
 
     println!("Exiting aurorus. Goodbye!");
     Ok(())
-}
\ No newline at end of file
+}